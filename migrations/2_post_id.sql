@@ -0,0 +1,20 @@
+-- Separate the external (booru) post ID from the internal IQDB ID (the rowid).
+-- Existing rows keep their rowid as their post ID.
+CREATE TABLE images_new (
+        id INTEGER PRIMARY KEY,
+        post_id INTEGER NOT NULL UNIQUE,
+        avglf0 REAL NOT NULL,
+        avglf1 REAL NOT NULL,
+        avglf2 REAL NOT NULL,
+        sig0 BLOB,
+        sig1 BLOB,
+        sig2 BLOB
+);
+
+INSERT INTO images_new ( id, post_id, avglf0, avglf1, avglf2, sig0, sig1, sig2 )
+SELECT id, id, avglf0, avglf1, avglf2, sig0, sig1, sig2
+FROM images;
+
+DROP TABLE images;
+
+ALTER TABLE images_new RENAME TO images;
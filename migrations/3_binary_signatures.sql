@@ -0,0 +1,27 @@
+-- Store the signature coefficients as a single versioned binary blob instead of three JSON
+-- arrays. The blob is a version byte (1), followed by the 3 x 40 coefficients as little-endian
+-- int16, in channel order.
+CREATE TABLE images_new (
+        id INTEGER PRIMARY KEY,
+        post_id INTEGER NOT NULL UNIQUE,
+        avglf0 REAL NOT NULL,
+        avglf1 REAL NOT NULL,
+        avglf2 REAL NOT NULL,
+        sig BLOB NOT NULL
+);
+
+INSERT INTO images_new ( id, post_id, avglf0, avglf1, avglf2, sig )
+SELECT id, post_id, avglf0, avglf1, avglf2, unhex(
+        '01'
+        || (SELECT group_concat(printf('%02X%02X', value & 255, (value >> 8) & 255), '' ORDER BY key)
+            FROM json_each(CAST(sig0 AS TEXT), '$.sig'))
+        || (SELECT group_concat(printf('%02X%02X', value & 255, (value >> 8) & 255), '' ORDER BY key)
+            FROM json_each(CAST(sig1 AS TEXT), '$.sig'))
+        || (SELECT group_concat(printf('%02X%02X', value & 255, (value >> 8) & 255), '' ORDER BY key)
+            FROM json_each(CAST(sig2 AS TEXT), '$.sig'))
+)
+FROM images;
+
+DROP TABLE images;
+
+ALTER TABLE images_new RENAME TO images;
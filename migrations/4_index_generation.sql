@@ -0,0 +1,22 @@
+-- Counts the changes made to the images table, so an on-disk snapshot of the in-memory index can
+-- tell if it's still up to date with the database.
+CREATE TABLE index_generation (
+    id         INTEGER PRIMARY KEY CHECK (id = 0),
+    generation INTEGER NOT NULL
+);
+INSERT INTO index_generation (id, generation) VALUES (0, 0);
+
+CREATE TRIGGER images_insert_generation AFTER INSERT ON images
+BEGIN
+    UPDATE index_generation SET generation = generation + 1 WHERE id = 0;
+END;
+
+CREATE TRIGGER images_update_generation AFTER UPDATE ON images
+BEGIN
+    UPDATE index_generation SET generation = generation + 1 WHERE id = 0;
+END;
+
+CREATE TRIGGER images_delete_generation AFTER DELETE ON images
+BEGIN
+    UPDATE index_generation SET generation = generation + 1 WHERE id = 0;
+END;
@@ -0,0 +1,269 @@
+//! Adding many posts at once, in chunks that each take one index lock and database transaction.
+
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use serde::Serialize;
+
+use crate::decode::Decoder;
+use crate::error::{Error, Result};
+use crate::export::ImageRecord;
+use crate::iqdb::{AddStatus, PostId, IQDB};
+use crate::signature::HaarSignature;
+
+/// How many records are added under one lock and database transaction.
+const CHUNK_SIZE: usize = 256;
+
+/// The outcome of every record of a batch, in the order they were pushed.
+#[derive(Debug, Default, Serialize)]
+pub struct BatchReport {
+    pub inserted: usize,
+    pub updated: usize,
+    pub failed: usize,
+    pub items: Vec<BatchItem>,
+    /// Why the input broke off, if it did. The records read before it are still reported.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub error: Option<BatchError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchError {
+    pub error: &'static str,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchItem {
+    /// Position of the record in the batch, counting from 0.
+    pub index: usize,
+    /// `None` if the record had no readable post ID.
+    pub post_id: Option<PostId>,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Outcome {
+    Added {
+        status: AddStatus,
+        hash: String,
+    },
+    Failed {
+        error: &'static str,
+        message: String,
+    },
+}
+
+/// Where a record's signature comes from.
+pub enum Input {
+    /// An image file, which is decoded within the [`Decoder`]'s limits.
+    File(Bytes),
+    /// A signature hash, like "iqdb_...".
+    Hash(String),
+    /// A line of an export, see [`ImageRecord`].
+    Record(ImageRecord),
+}
+
+/// A record that was pushed, but whose signature isn't computed yet.
+struct Pending {
+    index: usize,
+    record: Result<(PostId, Input)>,
+}
+
+/// Collects records into chunks and adds them, keeping track of the outcomes.
+///
+/// Signatures are computed on the blocking thread pool, at most `max_concurrent_decodes` at a
+/// time. Records that fail are reported and skipped, and a chunk that can't be stored is reported
+/// as failed as a whole.
+pub struct Batch<'a> {
+    iqdb: &'a IQDB,
+    decoder: &'a Decoder,
+    pending: Vec<Pending>,
+    pushed: usize,
+    report: BatchReport,
+}
+
+impl<'a> Batch<'a> {
+    pub fn new(iqdb: &'a IQDB, decoder: &'a Decoder) -> Self {
+        Batch {
+            iqdb,
+            decoder,
+            pending: Vec::new(),
+            pushed: 0,
+            report: BatchReport::default(),
+        }
+    }
+
+    /// Adds a record, or reports it as failed if it's an error. The records are added once a
+    /// chunk is full, or on [`Batch::finish`].
+    pub async fn push(&mut self, record: Result<(PostId, Input)>) {
+        self.pending.push(Pending {
+            index: self.pushed,
+            record,
+        });
+        self.pushed += 1;
+        if self.pending.len() >= CHUNK_SIZE {
+            self.flush().await;
+        }
+    }
+
+    /// Pushes every line of an NDJSON `body` as an [`ImageRecord`], adding chunks as it's read.
+    /// Blank lines are skipped.
+    ///
+    /// Fails with [`Error::TooLarge`] if a line is longer than `max_body_bytes`, keeping the
+    /// chunks before it.
+    pub async fn push_ndjson<S, E>(&mut self, body: S) -> Result<()>
+    where
+        S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        let max_line = self.decoder.limits().max_body_bytes;
+        let mut body = body;
+        let mut buf: Vec<u8> = Vec::new();
+        loop {
+            let chunk = body
+                .try_next()
+                .await
+                .map_err(|e| Error::Validation(e.to_string()))?;
+            let done = chunk.is_none();
+            buf.extend_from_slice(&chunk.unwrap_or_default());
+
+            // The last line may not end in a newline
+            let mut lines: Vec<Vec<u8>> = Vec::new();
+            while let Some(end) = buf.iter().position(|&b| b == b'\n') {
+                lines.push(buf.drain(..=end).collect());
+            }
+            if done {
+                lines.push(std::mem::take(&mut buf));
+            }
+
+            for line in lines {
+                if line.trim_ascii().is_empty() {
+                    continue;
+                }
+                let record = serde_json::from_slice::<ImageRecord>(&line)
+                    .map(|r| (r.post_id, Input::Record(r)))
+                    .map_err(|e| Error::Validation(format!("bad record: {}", e)));
+                self.push(record).await;
+            }
+            if done {
+                return Ok(());
+            }
+            // The complete lines before it are pushed
+            if buf.len() > max_line {
+                return Err(Error::TooLarge(format!(
+                    "record {} is over {} bytes",
+                    self.pushed, max_line
+                )));
+            }
+        }
+    }
+
+    /// Adds the records still pending and returns the outcomes.
+    pub async fn finish(mut self) -> BatchReport {
+        self.flush().await;
+        self.report
+    }
+
+    /// Like [`Batch::finish`], but records `read`'s error if the input broke off, so the report
+    /// still tells which records were added.
+    pub async fn finish_after(self, read: Result<()>) -> BatchReport {
+        let mut report = self.finish().await;
+        if let Err(e) = read {
+            report.error = Some(BatchError {
+                error: e.kind(),
+                message: e.to_string(),
+            });
+        }
+        report
+    }
+
+    /// Computes the signatures of the pending records and adds the ones that worked.
+    async fn flush(&mut self) {
+        let start = self.report.items.len();
+        let decoder = self.decoder;
+        let computed: Vec<_> = stream::iter(std::mem::take(&mut self.pending))
+            .map(|pending| async move {
+                let index = pending.index;
+                match pending.record {
+                    Ok((post_id, input)) => match signature(decoder, input).await {
+                        Ok(sig) => Ok((index, post_id, sig)),
+                        Err(e) => Err((index, Some(post_id), e)),
+                    },
+                    Err(e) => Err((index, None, e)),
+                }
+            })
+            .buffered(decoder.limits().max_concurrent_decodes)
+            .collect()
+            .await;
+
+        let mut indexes = Vec::new();
+        let mut images = Vec::new();
+        for result in computed {
+            match result {
+                Ok((index, post_id, sig)) => {
+                    indexes.push(index);
+                    images.push((post_id, sig));
+                }
+                Err((index, post_id, e)) => self.fail(index, post_id, &e),
+            }
+        }
+        if !images.is_empty() {
+            self.add(indexes, images).await;
+        }
+        // Failures were reported first
+        self.report.items[start..].sort_by_key(|item| item.index);
+    }
+
+    async fn add(&mut self, indexes: Vec<usize>, images: Vec<(PostId, HaarSignature)>) {
+        match self.iqdb.add_images(&images).await {
+            Ok(statuses) => {
+                for ((index, (post_id, sig)), status) in
+                    indexes.into_iter().zip(images).zip(statuses)
+                {
+                    match status {
+                        AddStatus::Inserted => self.report.inserted += 1,
+                        AddStatus::Updated => self.report.updated += 1,
+                    }
+                    self.report.items.push(BatchItem {
+                        index,
+                        post_id: Some(post_id),
+                        outcome: Outcome::Added {
+                            status,
+                            hash: sig.to_hash(),
+                        },
+                    });
+                }
+            }
+            // The whole chunk was rolled back
+            Err(e) => {
+                for (index, (post_id, _)) in indexes.into_iter().zip(images) {
+                    self.fail(index, Some(post_id), &e);
+                }
+            }
+        }
+    }
+
+    fn fail(&mut self, index: usize, post_id: Option<PostId>, e: &Error) {
+        self.report.failed += 1;
+        self.report.items.push(BatchItem {
+            index,
+            post_id,
+            outcome: Outcome::Failed {
+                error: e.kind(),
+                message: e.to_string(),
+            },
+        });
+    }
+}
+
+async fn signature(decoder: &Decoder, input: Input) -> Result<HaarSignature> {
+    match input {
+        Input::File(bytes) => {
+            let img = decoder.decode(bytes).await?;
+            decoder.signature(img).await
+        }
+        Input::Hash(hash) => Ok(hash.trim().parse()?),
+        Input::Record(record) => record.signature(),
+    }
+}
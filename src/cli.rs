@@ -0,0 +1,372 @@
+use bytes::Bytes;
+use clap::{Parser, Subcommand};
+use futures::{stream, TryStreamExt};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::task;
+use tracing::{error, info};
+
+use oiqdb::batch::{Batch, Outcome};
+use oiqdb::config::{Config, Flags};
+use oiqdb::decode::Decoder;
+use oiqdb::error::{Error, Result};
+use oiqdb::iqdb::{AddStatus, PostId, IQDB};
+use oiqdb::legacy::{self, ImportReport};
+use oiqdb::server;
+use oiqdb::signature::HaarSignature;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "An opinionated image querying database")]
+pub struct Cli {
+    #[command(flatten)]
+    pub flags: Flags,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// What to do. Without a command, the HTTP server is run.
+///
+/// Commands other than `http` work on the database directly. A server running at the same time
+/// doesn't see their changes until it loads the index again.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Serve the HTTP API
+    Http,
+    /// Add the image in FILE as POST_ID, replacing its signature if it was added before
+    Add { post_id: PostId, file: PathBuf },
+    /// Add every image in DIR, taking each post ID from the file name, like 1234.jpg
+    AddDir { dir: PathBuf },
+    /// Print the posts most similar to the image in FILE, best match first
+    Query {
+        file: PathBuf,
+        /// Number of matches to print
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Remove POST_ID
+    Rm { post_id: PostId },
+    /// Print the signature hash of the image in FILE, without adding it
+    Sig { file: PathBuf },
+    /// Print statistics about the index and the database
+    Stats,
+    /// Write every stored post as NDJSON, one {"post_id", "hash", ...} record per line
+    Export {
+        /// File to write to, instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Add every record of an NDJSON export in FILE, or stdin if FILE is -
+    Import { file: PathBuf },
+    /// Add every post of the SQLite database of the C++ iqdb in FILE
+    ImportSqlite { file: PathBuf },
+    /// Add every image of the binary database of the original iqdb in FILE
+    ImportBinary {
+        file: PathBuf,
+        /// Only check FILE and build an index from it, without storing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+/// Runs `command`, which is `http` if it's `None`.
+pub async fn run(command: Option<Command>, config: Config) -> Result<()> {
+    match command.unwrap_or(Command::Http) {
+        Command::Http => http(config).await,
+        Command::Add { post_id, file } => {
+            let iqdb = IQDB::connect(&config).await?;
+            let decoder = Decoder::new(config.limits.clone(), config.resampler);
+            let (status, hash) = add(&iqdb, &decoder, post_id, &file).await?;
+            println!("{} post {}: {}", status_name(status), post_id, hash);
+            Ok(())
+        }
+        Command::AddDir { dir } => add_dir(&config, &dir).await,
+        Command::Query { file, limit } => {
+            let limit = limit.unwrap_or(config.query.default_limit);
+            let decoder = Decoder::new(config.limits.clone(), config.resampler);
+            let sig = signature(&decoder, &file).await?;
+            let iqdb = IQDB::new(&config).await?;
+            for m in iqdb.query(&sig, limit).await? {
+                println!("{}\t{:.2}", m.id, m.score);
+            }
+            Ok(())
+        }
+        Command::Rm { post_id } => {
+            let iqdb = IQDB::connect(&config).await?;
+            iqdb.remove_image(post_id).await?;
+            println!("removed post {}", post_id);
+            Ok(())
+        }
+        Command::Sig { file } => {
+            let decoder = Decoder::new(config.limits.clone(), config.resampler);
+            println!("{}", signature(&decoder, &file).await?.to_hash());
+            Ok(())
+        }
+        Command::Export { output } => export(&config, output.as_deref()).await,
+        Command::Import { file } => import(&config, &file).await,
+        Command::ImportSqlite { file } => {
+            let iqdb = IQDB::connect(&config).await?;
+            let report = legacy::sqlite::import(&iqdb, &file).await?;
+            print_import(&report)
+        }
+        Command::ImportBinary {
+            file,
+            dry_run: false,
+        } => {
+            let iqdb = IQDB::connect(&config).await?;
+            let report = legacy::binary::import(&iqdb, &file).await?;
+            print_import(&report)
+        }
+        Command::ImportBinary {
+            file,
+            dry_run: true,
+        } => {
+            let mut db = task::spawn_blocking(move || legacy::binary::read(&file)).await??;
+            let report = std::mem::take(&mut db.report);
+            let stats = task::spawn_blocking(move || db.into_img_bin().stats()).await?;
+            print_failures(&report);
+            println!(
+                "read {} records, {} failed, indexed {} images with {} postings, stored nothing",
+                report.rows, report.failed, stats.images, stats.postings
+            );
+            check_failures(&report)
+        }
+        Command::Stats => {
+            let iqdb = IQDB::new(&config).await?;
+            let status = iqdb.status().await?;
+            let json = serde_json::to_string_pretty(&status)
+                .map_err(|e| Error::Internal(e.to_string()))?;
+            println!("{}", json);
+            Ok(())
+        }
+    }
+}
+
+/// Serves the HTTP API until a shutdown signal, then saves the index snapshot.
+async fn http(config: Config) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(config.bind).await?;
+    let iqdb = IQDB::connect(&config).await?;
+
+    // Requests are served while the index loads, see "/readyz"
+    let loader = iqdb.clone();
+    tokio::spawn(async move {
+        if let Err(e) = loader.load().await {
+            error!("could not load the index: {}", e);
+            std::process::exit(1);
+        }
+    });
+
+    info!("listening on {}", config.bind);
+    axum::serve(listener, server::app(iqdb.clone(), &config))
+        .with_graceful_shutdown(server::shutdown_signal())
+        .await?;
+
+    // The server has drained, so the snapshot catches every write
+    if !iqdb.progress.is_ready() {
+        info!("not saving a snapshot, the index was still loading");
+    } else if let Some(path) = iqdb.save_snapshot().await? {
+        info!("saved snapshot {}", path.display());
+    }
+    Ok(())
+}
+
+/// Adds every file in `dir` named after a post ID. Failures are reported and skipped, and make
+/// the command fail once the rest are added.
+async fn add_dir(config: &Config, dir: &Path) -> Result<()> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    let iqdb = IQDB::connect(config).await?;
+    let decoder = Decoder::new(config.limits.clone(), config.resampler);
+    let (mut added, mut failed) = (0, 0);
+    for file in files {
+        let post_id = file
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<PostId>().ok());
+        let Some(post_id) = post_id else {
+            eprintln!("skipped {}: not named after a post ID", file.display());
+            continue;
+        };
+        match add(&iqdb, &decoder, post_id, &file).await {
+            Ok((status, hash)) => {
+                added += 1;
+                println!("{} post {}: {}", status_name(status), post_id, hash);
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("could not add {}: {}", file.display(), e);
+            }
+        }
+    }
+    println!("added {} images, {} failed", added, failed);
+    if failed > 0 {
+        return Err(Error::Validation(format!(
+            "{} images could not be added",
+            failed
+        )));
+    }
+    Ok(())
+}
+
+async fn export(config: &Config, output: Option<&Path>) -> Result<()> {
+    let iqdb = IQDB::connect(config).await?;
+    let mut out: Box<dyn AsyncWrite + Unpin> = match output {
+        Some(path) => Box::new(tokio::fs::File::create(path).await?),
+        None => Box::new(tokio::io::stdout()),
+    };
+    let mut lines = oiqdb::export::export(iqdb.sql.clone());
+    while let Some(chunk) = lines.try_next().await? {
+        out.write_all(&chunk).await?;
+    }
+    out.flush().await?;
+    Ok(())
+}
+
+/// Adds the records of an export, reporting the ones that failed. Like `add-dir`, failures make
+/// the command fail once the rest are added.
+async fn import(config: &Config, file: &Path) -> Result<()> {
+    let input: Box<dyn AsyncRead + Unpin> = if file == Path::new("-") {
+        Box::new(tokio::io::stdin())
+    } else {
+        Box::new(tokio::fs::File::open(file).await?)
+    };
+    // Reads the input in chunks, as a stream of bytes
+    let body = stream::unfold(input, |mut input| async move {
+        let mut buf = vec![0; 64 * 1024];
+        match input.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), input))
+            }
+            Err(e) => Some((Err(e), input)),
+        }
+    });
+
+    let iqdb = IQDB::connect(config).await?;
+    let decoder = Decoder::new(config.limits.clone(), config.resampler);
+    let mut batch = Batch::new(&iqdb, &decoder);
+    let read = batch.push_ndjson(Box::pin(body)).await;
+    let report = batch.finish().await;
+    for item in report.items.iter() {
+        if let Outcome::Failed { message, .. } = &item.outcome {
+            eprintln!("record {} failed: {}", item.index, message);
+        }
+    }
+    println!(
+        "imported {} records, {} added, {} updated, {} failed",
+        report.items.len(),
+        report.inserted,
+        report.updated,
+        report.failed
+    );
+    // The records before a read error are added and reported all the same
+    read?;
+    if report.failed > 0 {
+        return Err(Error::Validation(format!(
+            "{} records could not be imported",
+            report.failed
+        )));
+    }
+    Ok(())
+}
+
+/// Prints the rows of a legacy import that failed and a summary, failing if any did.
+fn print_import(report: &ImportReport) -> Result<()> {
+    print_failures(report);
+    println!(
+        "imported {} rows, {} added, {} updated, {} failed",
+        report.rows, report.inserted, report.updated, report.failed
+    );
+    check_failures(report)
+}
+
+fn print_failures(report: &ImportReport) {
+    for failure in report.failures.iter() {
+        match failure.post_id {
+            Some(post_id) => eprintln!(
+                "row {} (post {}) failed: {}",
+                failure.row_id, post_id, failure.message
+            ),
+            None => eprintln!("row {} failed: {}", failure.row_id, failure.message),
+        }
+    }
+}
+
+fn check_failures(report: &ImportReport) -> Result<()> {
+    if report.failed > 0 {
+        return Err(Error::Validation(format!(
+            "{} rows could not be imported",
+            report.failed
+        )));
+    }
+    Ok(())
+}
+
+async fn add(
+    iqdb: &IQDB,
+    decoder: &Decoder,
+    post_id: PostId,
+    file: &Path,
+) -> Result<(AddStatus, String)> {
+    let sig = signature(decoder, file).await?;
+    let status = iqdb.add_image(post_id, &sig).await?;
+    Ok((status, sig.to_hash()))
+}
+
+/// Decodes the image in `file` and computes its signature, within the upload limits.
+async fn signature(decoder: &Decoder, file: &Path) -> Result<HaarSignature> {
+    let bytes = tokio::fs::read(file).await?;
+    let img = decoder.decode(Bytes::from(bytes)).await?;
+    decoder.signature(img).await
+}
+
+fn status_name(status: AddStatus) -> &'static str {
+    match status {
+        AddStatus::Inserted => "added",
+        AddStatus::Updated => "updated",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        let cli = Cli::parse_from([
+            "oiqdb", "query", "a.png", "--limit", "3", "--bind", "[::1]:80",
+        ]);
+        assert!(matches!(
+            cli.command,
+            Some(Command::Query { limit: Some(3), .. })
+        ));
+        assert_eq!(cli.flags.bind, Some("[::1]:80".parse().unwrap()));
+
+        let cli = Cli::parse_from(["oiqdb", "add-dir", "images"]);
+        assert!(matches!(cli.command, Some(Command::AddDir { .. })));
+
+        let cli = Cli::parse_from(["oiqdb", "export", "-o", "posts.ndjson"]);
+        assert!(matches!(
+            cli.command,
+            Some(Command::Export { output: Some(_) })
+        ));
+
+        let cli = Cli::parse_from(["oiqdb", "import-binary", "iqdb.db", "--dry-run"]);
+        assert!(matches!(
+            cli.command,
+            Some(Command::ImportBinary { dry_run: true, .. })
+        ));
+
+        let cli = Cli::parse_from(["oiqdb"]);
+        assert!(cli.command.is_none());
+
+        assert!(Cli::try_parse_from(["oiqdb", "rm", "not-a-post"]).is_err());
+    }
+}
@@ -0,0 +1,370 @@
+#[cfg(feature = "server")]
+use clap::Parser;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::decode::UploadLimits;
+use crate::signature::Resampler;
+
+/// Server settings. Each one is taken from the first of the command line flags, the environment,
+/// the TOML file given by `--config`, or the default.
+///
+/// Only `database` and `snapshot_path` matter to [`IQDB`](crate::IQDB) when it's used as a
+/// library.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: SocketAddr,
+    /// One of "error", "warn", "info", "debug" or "trace".
+    pub log_level: String,
+    /// Where the index snapshot is kept. Snapshots are off without one.
+    pub snapshot_path: Option<PathBuf>,
+    /// How images are scaled down before their signature is computed, like "gd" or "triangle".
+    /// Only "gd" gives the same signatures as the C++ iqdb.
+    pub resampler: Resampler,
+    pub database: DatabaseConfig,
+    pub limits: UploadLimits,
+    pub query: QueryConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    /// Pragmas set on every connection, like `journal_mode = "WAL"`.
+    pub pragmas: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueryConfig {
+    /// Number of matches returned when a query doesn't ask for a number.
+    pub default_limit: usize,
+    /// Largest number of matches a query may ask for.
+    pub max_limit: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            log_level: "info".to_string(),
+            snapshot_path: None,
+            resampler: Resampler::default(),
+            database: DatabaseConfig::default(),
+            limits: UploadLimits::default(),
+            query: QueryConfig::default(),
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: "sqlite://sqlite.db".to_string(),
+            pragmas: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for QueryConfig {
+    fn default() -> Self {
+        QueryConfig {
+            default_limit: 10,
+            max_limit: 1000,
+        }
+    }
+}
+
+/// Command line flags, which can also be set through the environment. They're accepted before
+/// or after any command.
+#[cfg(feature = "server")]
+#[derive(Debug, Default, Parser)]
+pub struct Flags {
+    /// TOML file to read settings from
+    #[arg(long, env = "OIQDB_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on, like 0.0.0.0:3000
+    #[arg(long, env = "OIQDB_BIND", global = true)]
+    pub bind: Option<SocketAddr>,
+    #[arg(long, env = "OIQDB_LOG_LEVEL", global = true)]
+    pub log_level: Option<String>,
+    #[arg(long, env = "SNAPSHOT_PATH", global = true)]
+    pub snapshot_path: Option<PathBuf>,
+    /// How images are scaled down: gd, nearest, triangle, catmullrom, gaussian or lanczos3
+    #[arg(long, env = "OIQDB_RESAMPLER", global = true)]
+    pub resampler: Option<Resampler>,
+    #[arg(long, env = "DATABASE_URL", global = true)]
+    pub database_url: Option<String>,
+    /// SQLite pragma to set on every connection, as name=value. Can be repeated
+    #[arg(long = "pragma", value_name = "NAME=VALUE", global = true)]
+    pub pragmas: Vec<String>,
+    #[arg(long, env = "OIQDB_MAX_BODY_BYTES", global = true)]
+    pub max_body_bytes: Option<usize>,
+    #[arg(long, env = "OIQDB_MAX_WIDTH", global = true)]
+    pub max_width: Option<u32>,
+    #[arg(long, env = "OIQDB_MAX_HEIGHT", global = true)]
+    pub max_height: Option<u32>,
+    #[arg(long, env = "OIQDB_MAX_DECODE_ALLOC", global = true)]
+    pub max_decode_alloc: Option<u64>,
+    #[arg(long, env = "OIQDB_DECODE_TIMEOUT_MS", global = true)]
+    pub decode_timeout_ms: Option<u64>,
+    #[arg(long, env = "OIQDB_MAX_CONCURRENT_DECODES", global = true)]
+    pub max_concurrent_decodes: Option<usize>,
+    #[arg(long, env = "OIQDB_DEFAULT_QUERY_LIMIT", global = true)]
+    pub default_query_limit: Option<usize>,
+    #[arg(long, env = "OIQDB_MAX_QUERY_LIMIT", global = true)]
+    pub max_query_limit: Option<usize>,
+}
+
+/// Why the settings couldn't be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "could not parse {}: {}", path.display(), e),
+            ConfigError::Invalid(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads the file given by `flags`, if any, applies the flags over it and validates the
+    /// result.
+    #[cfg(feature = "server")]
+    pub fn load(flags: &Flags) -> Result<Self, ConfigError> {
+        let mut config = match &flags.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        config.apply(flags)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_owned(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_owned(), e))
+    }
+
+    #[cfg(feature = "server")]
+    fn apply(&mut self, flags: &Flags) -> Result<(), ConfigError> {
+        fn set<T: Clone>(field: &mut T, flag: &Option<T>) {
+            if let Some(value) = flag {
+                *field = value.clone();
+            }
+        }
+        set(&mut self.bind, &flags.bind);
+        set(&mut self.log_level, &flags.log_level);
+        if flags.snapshot_path.is_some() {
+            self.snapshot_path.clone_from(&flags.snapshot_path);
+        }
+        set(&mut self.resampler, &flags.resampler);
+        set(&mut self.database.url, &flags.database_url);
+        for pragma in flags.pragmas.iter() {
+            let Some((name, value)) = pragma.split_once('=') else {
+                return Err(ConfigError::Invalid(format!(
+                    "pragma \"{}\" must be given as name=value",
+                    pragma
+                )));
+            };
+            self.database
+                .pragmas
+                .insert(name.trim().to_string(), value.trim().to_string());
+        }
+        set(&mut self.limits.max_body_bytes, &flags.max_body_bytes);
+        set(&mut self.limits.max_width, &flags.max_width);
+        set(&mut self.limits.max_height, &flags.max_height);
+        set(&mut self.limits.max_decode_alloc, &flags.max_decode_alloc);
+        if let Some(ms) = flags.decode_timeout_ms {
+            self.limits.decode_timeout = std::time::Duration::from_millis(ms);
+        }
+        set(
+            &mut self.limits.max_concurrent_decodes,
+            &flags.max_concurrent_decodes,
+        );
+        set(&mut self.query.default_limit, &flags.default_query_limit);
+        set(&mut self.query.max_limit, &flags.max_query_limit);
+        Ok(())
+    }
+
+    /// Checks the settings make sense together, explaining the first one that doesn't.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: String| Err(ConfigError::Invalid(msg));
+        if self.log_level.parse::<tracing::Level>().is_err() {
+            return invalid(format!(
+                "log_level \"{}\" must be one of error, warn, info, debug or trace",
+                self.log_level
+            ));
+        }
+        if !self.database.url.starts_with("sqlite:") {
+            return invalid(format!(
+                "database url \"{}\" must start with sqlite:",
+                self.database.url
+            ));
+        }
+        let is_word = |s: &str| {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+        };
+        for (name, value) in self.database.pragmas.iter() {
+            if !is_word(name) || !is_word(value) {
+                return invalid(format!(
+                    "pragma {} = \"{}\" may only use letters, digits, '_', '-' and '.'",
+                    name, value
+                ));
+            }
+        }
+        let limits = &self.limits;
+        for (name, value) in [
+            ("limits.max_body_bytes", limits.max_body_bytes as u64),
+            ("limits.max_width", limits.max_width as u64),
+            ("limits.max_height", limits.max_height as u64),
+            ("limits.max_decode_alloc", limits.max_decode_alloc),
+            (
+                "limits.decode_timeout_ms",
+                limits.decode_timeout.as_millis() as u64,
+            ),
+            (
+                "limits.max_concurrent_decodes",
+                limits.max_concurrent_decodes as u64,
+            ),
+            ("query.max_limit", self.query.max_limit as u64),
+        ] {
+            if value == 0 {
+                return invalid(format!("{} must be greater than 0", name));
+            }
+        }
+        if self.query.default_limit > self.query.max_limit {
+            return invalid(format!(
+                "query.default_limit ({}) must not be greater than query.max_limit ({})",
+                self.query.default_limit, self.query.max_limit
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> tracing::Level {
+        self.log_level.parse().unwrap_or(tracing::Level::INFO)
+    }
+
+    /// The default settings with the database from `.env`, for tests against the dev database.
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        dotenvy::dotenv().ok();
+        let mut config = Config::default();
+        if let Ok(url) = std::env::var("DATABASE_URL") {
+            config.database.url = url;
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "server")]
+    fn file_then_flags() {
+        let path = std::env::temp_dir().join(format!("oiqdb-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            bind = "127.0.0.1:4000"
+            snapshot_path = "index.snapshot"
+            resampler = "triangle"
+
+            [database]
+            url = "sqlite://from-file.db"
+            pragmas = { journal_mode = "WAL" }
+
+            [limits]
+            max_width = 4096
+            decode_timeout_ms = 2500
+
+            [query]
+            max_limit = 50
+            "#,
+        )
+        .unwrap();
+
+        let flags = Flags::parse_from([
+            "oiqdb",
+            "--config",
+            path.to_str().unwrap(),
+            "--database-url",
+            "sqlite://from-flag.db",
+            "--pragma",
+            "synchronous=NORMAL",
+            "--resampler",
+            "lanczos3",
+        ]);
+        let config = Config::load(&flags).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.bind, "127.0.0.1:4000".parse().unwrap());
+        assert_eq!(config.snapshot_path, Some(PathBuf::from("index.snapshot")));
+        assert_eq!(config.resampler.to_string(), "lanczos3");
+        assert_eq!(config.database.url, "sqlite://from-flag.db");
+        assert_eq!(config.database.pragmas["journal_mode"], "WAL");
+        assert_eq!(config.database.pragmas["synchronous"], "NORMAL");
+        assert_eq!(config.limits.max_width, 4096);
+        assert_eq!(config.limits.max_height, UploadLimits::default().max_height);
+        assert_eq!(
+            config.limits.decode_timeout,
+            std::time::Duration::from_millis(2500)
+        );
+        assert_eq!(config.query.max_limit, 50);
+        assert_eq!(config.query.default_limit, 10);
+    }
+
+    #[test]
+    fn readable_errors() {
+        let err = |config: Config| config.validate().unwrap_err().to_string();
+
+        let config = Config {
+            log_level: "loud".to_string(),
+            ..Config::default()
+        };
+        assert!(err(config).contains("log_level \"loud\""));
+
+        let mut config = Config::default();
+        config.query.default_limit = 20;
+        config.query.max_limit = 5;
+        assert!(err(config).contains("query.default_limit (20)"));
+
+        let mut config = Config::default();
+        config.limits.max_concurrent_decodes = 0;
+        assert!(err(config).contains("limits.max_concurrent_decodes"));
+
+        let mut config = Config::default();
+        config.database.pragmas.insert(
+            "journal_mode".to_string(),
+            "WAL; DROP TABLE images".to_string(),
+        );
+        assert!(err(config).contains("pragma journal_mode"));
+
+        let parsed: Result<Config, _> = toml::from_str("bnid = \"0.0.0.0:1\"");
+        assert!(parsed.unwrap_err().to_string().contains("bnid"));
+        let parsed: Result<Config, _> = toml::from_str("resampler = \"bicubic\"");
+        assert!(parsed
+            .unwrap_err()
+            .to_string()
+            .contains("unknown resampler \"bicubic\""));
+    }
+}
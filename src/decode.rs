@@ -0,0 +1,202 @@
+use bytes::Bytes;
+use image::{DynamicImage, ImageError, ImageFormat, ImageReader};
+use serde::Deserialize;
+use serde_with::{serde_as, DurationMilliSeconds};
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task;
+
+use crate::error::{Error, Result};
+use crate::metrics::{Stage, METRICS};
+use crate::signature::{HaarSignature, Resampler};
+
+/// Bounds on what an upload may cost, so a single request can't exhaust the server.
+#[serde_as]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadLimits {
+    /// Largest request body, in bytes.
+    pub max_body_bytes: usize,
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Largest buffer the decoder may allocate, in bytes.
+    pub max_decode_alloc: u64,
+    #[serde(rename = "decode_timeout_ms")]
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub decode_timeout: Duration,
+    /// How many images are decoded at once. Further uploads wait for a turn.
+    pub max_concurrent_decodes: usize,
+}
+
+impl Default for UploadLimits {
+    fn default() -> Self {
+        UploadLimits {
+            max_body_bytes: 32 * 1024 * 1024,
+            max_width: 16384,
+            max_height: 16384,
+            max_decode_alloc: 512 * 1024 * 1024,
+            decode_timeout: Duration::from_secs(10),
+            max_concurrent_decodes: std::thread::available_parallelism().map_or(4, |n| n.get()),
+        }
+    }
+}
+
+/// Decodes uploads on the blocking thread pool within [`UploadLimits`], and computes their
+/// signatures with the configured [`Resampler`].
+#[derive(Clone)]
+pub struct Decoder {
+    limits: Arc<UploadLimits>,
+    permits: Arc<Semaphore>,
+    resampler: Resampler,
+}
+
+impl Decoder {
+    pub fn new(limits: UploadLimits, resampler: Resampler) -> Self {
+        Decoder {
+            permits: Arc::new(Semaphore::new(limits.max_concurrent_decodes)),
+            limits: Arc::new(limits),
+            resampler,
+        }
+    }
+
+    pub fn limits(&self) -> &UploadLimits {
+        &self.limits
+    }
+
+    /// Decodes `raw_data`, failing with [`Error::Limit`] if the image is too large or takes too
+    /// long to decode.
+    ///
+    /// A decode that times out can't be stopped, so it keeps its turn until it's done, and the
+    /// cap on concurrent decodes still holds.
+    pub async fn decode(&self, raw_data: Bytes) -> Result<DynamicImage> {
+        Ok(self.decode_with_format(raw_data).await?.0)
+    }
+
+    /// Computes the signature of a decoded image on the blocking thread pool.
+    ///
+    /// The image is resampled from its decoded pixels, so no other full size copy is made.
+    pub async fn signature(&self, img: DynamicImage) -> Result<HaarSignature> {
+        let resampler = self.resampler;
+        Ok(task::spawn_blocking(move || HaarSignature::from_image(img, resampler)).await?)
+    }
+
+    /// Like [`Decoder::decode`], and also returns the format the image was stored in.
+    pub async fn decode_with_format(&self, raw_data: Bytes) -> Result<(DynamicImage, ImageFormat)> {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        let limits = self.limits.clone();
+        let decode = task::spawn_blocking(move || {
+            let _permit = permit;
+            decode_image(raw_data, &limits)
+        });
+        match tokio::time::timeout(self.limits.decode_timeout, decode).await {
+            Ok(res) => res?,
+            Err(_) => Err(Error::Limit(format!(
+                "decoding the image took longer than {:?}",
+                self.limits.decode_timeout
+            ))),
+        }
+    }
+}
+
+fn decode_image(raw_data: Bytes, limits: &UploadLimits) -> Result<(DynamicImage, ImageFormat)> {
+    let _timer = METRICS.time(Stage::Decode);
+    let mut image_limits = image::Limits::default();
+    image_limits.max_image_width = Some(limits.max_width);
+    image_limits.max_image_height = Some(limits.max_height);
+    image_limits.max_alloc = Some(limits.max_decode_alloc);
+
+    let mut reader = ImageReader::new(Cursor::new(raw_data)).with_guessed_format()?;
+    reader.limits(image_limits);
+    let format = reader.format();
+    let img = reader.decode().map_err(|e| match e {
+        ImageError::Limits(e) => Error::Limit(format!(
+            "the image is over the limits of {}x{} pixels and {} bytes: {}",
+            limits.max_width, limits.max_height, limits.max_decode_alloc, e
+        )),
+        e => Error::Decode(e),
+    })?;
+    // Decoding fails without a format, so there's always one here
+    Ok((img, format.unwrap_or(ImageFormat::Png)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, RgbImage};
+
+    fn png(width: u32, height: u32) -> Bytes {
+        let mut buf = Cursor::new(Vec::new());
+        RgbImage::new(width, height)
+            .write_to(&mut buf, ImageFormat::Png)
+            .unwrap();
+        Bytes::from(buf.into_inner())
+    }
+
+    #[tokio::test]
+    async fn decode_within_limits() {
+        let decoder = Decoder::new(
+            UploadLimits {
+                max_width: 64,
+                max_height: 32,
+                ..UploadLimits::default()
+            },
+            Resampler::default(),
+        );
+
+        let (img, format) = decoder.decode_with_format(png(64, 32)).await.unwrap();
+        assert_eq!((img.width(), img.height()), (64, 32));
+        assert_eq!(format, ImageFormat::Png);
+
+        let err = decoder.decode(png(65, 32)).await.unwrap_err();
+        assert_eq!(err.kind(), "limit");
+        let err = decoder.decode(png(64, 33)).await.unwrap_err();
+        assert_eq!(err.kind(), "limit");
+
+        let err = decoder
+            .decode(Bytes::from_static(b"not an image"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), "decode");
+    }
+
+    #[tokio::test]
+    async fn decode_allocation_limit() {
+        let decoder = Decoder::new(
+            UploadLimits {
+                max_decode_alloc: 1024,
+                ..UploadLimits::default()
+            },
+            Resampler::default(),
+        );
+        let err = decoder.decode(png(64, 64)).await.unwrap_err();
+        assert_eq!(err.kind(), "limit");
+    }
+
+    #[tokio::test]
+    async fn signature_with_resampler() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 48, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8])
+        }));
+        let mut hashes = Vec::new();
+        for resampler in [
+            Resampler::Gd,
+            Resampler::Filter(image::imageops::FilterType::Nearest),
+        ] {
+            let decoder = Decoder::new(UploadLimits::default(), resampler);
+            let hash = decoder.signature(img.clone()).await.unwrap().to_hash();
+            assert_eq!(
+                hash,
+                HaarSignature::from_image(img.clone(), resampler).to_hash()
+            );
+            hashes.push(hash);
+        }
+        assert_ne!(hashes[0], hashes[1]);
+    }
+}
@@ -0,0 +1,192 @@
+#[cfg(feature = "server")]
+use axum::{
+    extract::multipart::MultipartError,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+#[cfg(feature = "server")]
+use serde::Serialize;
+use std::fmt;
+use std::io;
+
+use crate::signature::{InvalidSignature, ParseHashError};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything that can go wrong while adding, removing or querying images.
+///
+/// In the HTTP API, each kind maps to one status code, and responds with a JSON body like
+/// `{"error": "not_found", "message": "no post 5"}`.
+#[derive(Debug)]
+pub enum Error {
+    /// An upload couldn't be decoded as an image.
+    Decode(image::ImageError),
+    /// The database or the snapshot couldn't be read or written.
+    Storage(Box<dyn std::error::Error + Send + Sync>),
+    NotFound(String),
+    /// The request clashes with the stored data, like a post ID that's already taken.
+    Conflict(String),
+    /// The request itself is malformed, like a missing field or a bad hash.
+    Validation(String),
+    /// The request body is over the size limit.
+    TooLarge(String),
+    /// An upload is over the image size limits, or took too long to decode.
+    Limit(String),
+    /// The index isn't loaded yet.
+    Unavailable(String),
+    /// A bug, like a panic in a blocking task.
+    Internal(String),
+}
+
+#[cfg(feature = "server")]
+#[derive(Serialize)]
+struct ErrorBody {
+    error: &'static str,
+    message: String,
+}
+
+impl Error {
+    #[cfg(feature = "server")]
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::Decode(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::Validation(_) => StatusCode::BAD_REQUEST,
+            Error::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Limit(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// A short, stable name of the error kind, for clients to match on.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Decode(_) => "decode",
+            Error::Storage(_) => "storage",
+            Error::NotFound(_) => "not_found",
+            Error::Conflict(_) => "conflict",
+            Error::Validation(_) => "validation",
+            Error::TooLarge(_) => "too_large",
+            Error::Limit(_) => "limit",
+            Error::Unavailable(_) => "unavailable",
+            Error::Internal(_) => "internal",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Decode(e) => write!(f, "could not decode image: {}", e),
+            Error::Storage(e) => write!(f, "storage error: {}", e),
+            Error::NotFound(msg)
+            | Error::Conflict(msg)
+            | Error::Validation(msg)
+            | Error::TooLarge(msg)
+            | Error::Limit(msg)
+            | Error::Unavailable(msg)
+            | Error::Internal(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Decode(e) => Some(e),
+            Error::Storage(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: self.kind(),
+            message: self.to_string(),
+        };
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::Database(ref db) if db.is_unique_violation() => {
+                Error::Conflict(db.message().to_string())
+            }
+            e => Error::Storage(Box::new(e)),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Storage(Box::new(e))
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(e: image::ImageError) -> Self {
+        Error::Decode(e)
+    }
+}
+
+#[cfg(feature = "server")]
+impl From<MultipartError> for Error {
+    fn from(e: MultipartError) -> Self {
+        match e.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => Error::TooLarge(e.body_text()),
+            _ => Error::Validation(e.body_text()),
+        }
+    }
+}
+
+impl From<ParseHashError> for Error {
+    fn from(e: ParseHashError) -> Self {
+        Error::Validation(e.to_string())
+    }
+}
+
+impl From<InvalidSignature> for Error {
+    fn from(e: InvalidSignature) -> Self {
+        Error::Validation(e.to_string())
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(e: tokio::task::JoinError) -> Self {
+        Error::Internal(e.to_string())
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_codes() {
+        assert_eq!(
+            Error::NotFound("no post 5".to_string()).status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            Error::from(
+                "iqdb_1234"
+                    .parse::<crate::signature::HaarSignature>()
+                    .unwrap_err()
+            )
+            .status(),
+            StatusCode::BAD_REQUEST
+        );
+        let e = Error::from(sqlx::Error::PoolTimedOut);
+        assert_eq!(e.kind(), "storage");
+        assert_eq!(e.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,153 @@
+//! A portable NDJSON format for moving stored posts between instances and keeping backups.
+//!
+//! Each line is an [`ImageRecord`], like
+//! `{"post_id": 1, "hash": "iqdb_...", "avglf": [0.5, 0.01, 0.02], "grayscale": false}`.
+//! [`export`] writes every stored post, and [`Batch::push_ndjson`](crate::batch::Batch::push_ndjson) reads
+//! them back.
+
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::{SinkExt, Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::iqdb::{PostId, Sql, SqlRow};
+use crate::signature::{haar, HaarSignature};
+
+/// Rows are sent in chunks of about this many bytes.
+const EXPORT_CHUNK_BYTES: usize = 64 * 1024;
+
+/// A stored post. Only `post_id` and `hash` are needed to import it. The rest describes the
+/// signature, and is checked against the hash when it's present.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ImageRecord {
+    pub post_id: PostId,
+    pub hash: String,
+    /// Average luminance of each YIQ channel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avglf: Option<haar::Lumin>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grayscale: Option<bool>,
+}
+
+impl From<&SqlRow> for ImageRecord {
+    fn from(row: &SqlRow) -> Self {
+        ImageRecord {
+            post_id: row.post_id,
+            hash: row.s.to_hash(),
+            avglf: Some(row.s.avglf),
+            grayscale: Some(row.s.is_grayscale()),
+        }
+    }
+}
+
+impl ImageRecord {
+    /// Parses the hash, failing with [`Error::Validation`] if it's malformed, holds values
+    /// [`HaarSignature::validate`] rejects, or doesn't match the metadata.
+    pub fn signature(&self) -> Result<HaarSignature> {
+        let sig: HaarSignature = self.hash.trim().parse()?;
+        if self.avglf.is_some_and(|avglf| avglf != sig.avglf) {
+            return Err(Error::Validation(format!(
+                "avglf of post {} doesn't match its hash",
+                self.post_id
+            )));
+        }
+        if self.grayscale.is_some_and(|g| g != sig.is_grayscale()) {
+            return Err(Error::Validation(format!(
+                "grayscale of post {} doesn't match its hash",
+                self.post_id
+            )));
+        }
+        Ok(sig)
+    }
+}
+
+/// Streams every stored post as NDJSON, by IQDB ID, in chunks of whole lines.
+///
+/// The rows are read by a task of their own, which stops early if the stream is dropped. A
+/// database error ends the stream after the rows read before it.
+pub fn export(sql: Sql) -> impl Stream<Item = Result<Bytes>> + Send + 'static {
+    let (mut tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        let mut rows = sql.each_image();
+        let mut buf: Vec<u8> = Vec::new();
+        loop {
+            let row = match rows.try_next().await {
+                Ok(Some(row)) => row,
+                Ok(None) => break,
+                Err(e) => {
+                    let _ = tx.send(Ok(Bytes::from(buf))).await;
+                    let _ = tx.send(Err(Error::from(e))).await;
+                    return;
+                }
+            };
+            serde_json::to_writer(&mut buf, &ImageRecord::from(&row))
+                .expect("records serialize to JSON");
+            buf.push(b'\n');
+            if buf.len() >= EXPORT_CHUNK_BYTES {
+                let chunk = Bytes::from(std::mem::take(&mut buf));
+                if tx.send(Ok(chunk)).await.is_err() {
+                    return;
+                }
+            }
+        }
+        if !buf.is_empty() {
+            let _ = tx.send(Ok(Bytes::from(buf))).await;
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_metadata_is_checked() {
+        let mut sig = HaarSignature::new();
+        sig.avglf = [0.5, 0.1, 0.2];
+        for (i, s) in sig.sig0.sig.iter_mut().enumerate() {
+            *s = i as i16 + 1;
+        }
+        sig.sig1 = sig.sig0.clone();
+        sig.sig2 = sig.sig0.clone();
+        let record = ImageRecord {
+            post_id: 7,
+            hash: sig.to_hash(),
+            avglf: Some(sig.avglf),
+            grayscale: Some(false),
+        };
+
+        let line = serde_json::to_string(&record).unwrap();
+        let parsed: ImageRecord = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed.signature().unwrap().to_hash(), record.hash);
+
+        let bare: ImageRecord =
+            serde_json::from_str(&format!(r#"{{"post_id": 7, "hash": "{}"}}"#, record.hash))
+                .unwrap();
+        assert!(bare.signature().is_ok());
+
+        let wrong = ImageRecord {
+            avglf: Some([0.4, 0.1, 0.2]),
+            ..parsed
+        };
+        assert_eq!(wrong.signature().unwrap_err().kind(), "validation");
+        let wrong = ImageRecord {
+            avglf: None,
+            grayscale: Some(true),
+            ..wrong
+        };
+        assert_eq!(wrong.signature().unwrap_err().kind(), "validation");
+
+        sig.sig2.sig[0] = i16::MIN;
+        let out_of_range = ImageRecord {
+            post_id: 7,
+            hash: sig.to_hash(),
+            avglf: None,
+            grayscale: None,
+        };
+        let err = out_of_range.signature().unwrap_err();
+        assert_eq!(err.kind(), "validation");
+        assert!(err.to_string().contains("out of range"), "{err}");
+    }
+}
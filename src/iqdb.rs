@@ -1,69 +1,374 @@
-use crate::iqdb::imgdb::{ImgBin, ImgBinState};
-use futures::TryStreamExt;
-use sqlx::Row;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::iqdb::imgdb::ImgBinState;
 use crate::signature::HaarSignature;
+use futures::TryStreamExt;
+use serde::Serialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tokio::task;
+use tracing::{info, trace, warn};
 
 mod db;
 mod imgdb;
+mod snapshot;
+
+pub use db::{Sql, SqlRow, StorageStats, Upsert};
+pub use imgdb::{BucketStats, ImgBin, IndexStats, IqdbId, PostId, SimValue};
+
+/// Whether [`IQDB::add_image`] added a new post or replaced an existing post's signature.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddStatus {
+    Inserted,
+    Updated,
+}
 
+/// An [`ImgBin`] kept in step with the signatures stored in a [`Sql`] database.
+///
+/// Clones share the same index and database, so one can be handed to each task.
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone)]
 pub struct IQDB {
-    pub state: ImgBinState,
-    pub sql: db::Sql,
+    pub(crate) state: ImgBinState,
+    pub sql: Sql,
+    // Where the index snapshot is kept, if enabled through `snapshot_path`.
+    snapshot: Option<PathBuf>,
+    // The database generation the index reflects: the one it was loaded at, moved forward by the
+    // writes made through this IQDB only. Changes by other processes leave it behind the
+    // database, so a snapshot tagged with it is seen as stale. Only changed under the write lock.
+    generation: Arc<AtomicI64>,
+    pub progress: Arc<LoadProgress>,
+}
+
+/// Everything reported by [`IQDB::status`].
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub load: LoadStatus,
+    pub index: IndexStats,
+    pub storage: StorageStats,
+}
+
+/// Tracks [`IQDB::load`], so the server can report on it while the index is loading.
+pub struct LoadProgress {
+    started: Instant,
+    loaded: AtomicU64,
+    total: AtomicU64,
+    // How long the load took, set once it's done.
+    finished: OnceLock<Duration>,
+}
+
+/// A point in time view of [`LoadProgress`].
+#[derive(Debug, Serialize)]
+pub struct LoadStatus {
+    pub ready: bool,
+    pub loaded: u64,
+    pub total: u64,
+    pub elapsed_secs: f64,
+    /// Estimated seconds left, from the load rate so far.
+    pub eta_secs: Option<f64>,
+}
+
+impl LoadProgress {
+    fn new() -> Self {
+        LoadProgress {
+            started: Instant::now(),
+            loaded: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            finished: OnceLock::new(),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.finished.get().is_some()
+    }
+
+    pub fn status(&self) -> LoadStatus {
+        let ready = self.is_ready();
+        let loaded = self.loaded.load(Ordering::Relaxed);
+        let total = self.total.load(Ordering::Relaxed);
+        let elapsed_secs = match self.finished.get() {
+            Some(took) => took.as_secs_f64(),
+            None => self.started.elapsed().as_secs_f64(),
+        };
+        let eta_secs = match ready {
+            true => Some(0.0),
+            false if loaded > 0 => {
+                Some(elapsed_secs * total.saturating_sub(loaded) as f64 / loaded as f64)
+            }
+            false => None,
+        };
+        LoadStatus {
+            ready,
+            loaded,
+            total,
+            elapsed_secs,
+            eta_secs,
+        }
+    }
 }
 
 impl IQDB {
     // this is load database lel
-    pub async fn new() -> sqlx::Result<Self, sqlx::Error> {
-        let sql = db::Sql::new().await;
+    /// Connects to the database and loads the index, see [`IQDB::connect`] and [`IQDB::load`].
+    pub async fn new(config: &Config) -> Result<Self> {
+        let iqdb = IQDB::connect(config).await?;
+        iqdb.load().await?;
+        Ok(iqdb)
+    }
+
+    /// Connects to the database, with an empty index that isn't ready until [`IQDB::load`] is
+    /// done.
+    pub async fn connect(config: &Config) -> Result<Self> {
+        let sql = Sql::connect(&config.database).await?;
         let state = ImgBinState {
-            data: Arc::new(Mutex::new(ImgBin::new())),
+            data: Arc::new(RwLock::new(ImgBin::new())),
         };
+        Ok(IQDB {
+            state,
+            sql,
+            snapshot: config.snapshot_path.clone(),
+            generation: Arc::new(AtomicI64::new(0)),
+            progress: Arc::new(LoadProgress::new()),
+        })
+    }
 
-        let sql_clone: db::Sql = sql.clone();
-        let mut sql_rows = sql_clone.each_image();
-        while let Some(r) = sql_rows.try_next().await? {
-            println!("the sqlite row was gotten: {}", r.id);
-            state.data.clone().lock().await.add_image_in_memory(r.id, r.id, &r.s);
-            if r.id % 250000 == 0 {
-                println!("loaded a bunch of images");
+    /// Loads the index, from the snapshot at `snapshot_path` when it's up to date with the
+    /// database, or else by replaying every row, and marks it ready.
+    ///
+    /// The index is built on the side and swapped in at the end, so `progress` can be polled
+    /// in the meantime.
+    pub async fn load(&self) -> Result<()> {
+        let total = self.sql.count_images().await?;
+        self.progress.total.store(total as u64, Ordering::Relaxed);
+
+        let img_bin = match &self.snapshot {
+            Some(path) => load_snapshot(&self.sql, path).await?,
+            None => None,
+        };
+        let (img_bin, generation) = match img_bin {
+            Some(loaded) => {
+                self.progress.loaded.store(total as u64, Ordering::Relaxed);
+                loaded
             }
+            None => replay(&self.sql, &self.progress).await?,
+        };
+
+        let mut data = self.state.write().await;
+        *data = img_bin;
+        self.generation.store(generation, Ordering::Relaxed);
+        drop(data);
+        let _ = self.progress.finished.set(self.progress.started.elapsed());
+        Ok(())
+    }
+
+    /// Writes the index snapshot, returning where it was written, or `None` if snapshots aren't
+    /// enabled.
+    ///
+    /// The snapshot is tagged with the generation the index reflects, rather than the database's,
+    /// so rows changed by another process since the index was loaded are replayed at the next
+    /// start. Writes wait for the snapshot to finish, so it always matches its tag.
+    pub async fn save_snapshot(&self) -> Result<Option<PathBuf>> {
+        let Some(path) = self.snapshot.clone() else {
+            return Ok(None);
+        };
+        // A partly loaded index would be saved as if it were complete
+        if !self.progress.is_ready() {
+            return Err(Error::Unavailable("the index isn't loaded yet".to_string()));
         }
+        let data = self.state.read().await;
+        let generation = self.generation.load(Ordering::Relaxed);
+        let file = path.clone();
+        task::spawn_blocking(move || snapshot::save(&file, &data, generation)).await??;
+        Ok(Some(path))
+    }
 
-        Ok(IQDB {
-            state: state,
-            sql: sql,
+    /// Adds `haar` for `post_id`, replacing the post's previous signature if it was added before.
+    ///
+    /// The index stays locked while the database is updated, so a replaced signature is removed
+    /// from memory together with the insert of the new one.
+    ///
+    /// Fails with [`Error::Validation`] before anything is stored unless `haar` passes
+    /// [`HaarSignature::validate`], since once the row is committed, nothing may fail until the
+    /// index is updated.
+    pub async fn add_image(&self, post_id: PostId, haar: &HaarSignature) -> Result<AddStatus> {
+        haar.validate()?;
+        let mut data = self.state.write().await;
+        let (upsert, advanced) = self.sql.upsert_signature(post_id, haar).await?;
+        self.generation.fetch_add(advanced, Ordering::Relaxed);
+        Ok(apply_upsert(&mut data, upsert, post_id, haar))
+    }
+
+    /// Adds every signature of `images` under a single lock and database transaction, so
+    /// either all of them are added or none are. See [`IQDB::add_image`].
+    pub async fn add_images(&self, images: &[(PostId, HaarSignature)]) -> Result<Vec<AddStatus>> {
+        for (post_id, haar) in images {
+            haar.validate()
+                .map_err(|e| Error::Validation(format!("post {}: {}", post_id, e)))?;
+        }
+        let mut data = self.state.write().await;
+        let (upserts, advanced) = self.sql.upsert_signatures(images).await?;
+        self.generation.fetch_add(advanced, Ordering::Relaxed);
+        Ok(upserts
+            .into_iter()
+            .zip(images)
+            .map(|(upsert, (post_id, haar))| apply_upsert(&mut data, upsert, *post_id, haar))
+            .collect())
+    }
+
+    /// Removes `post_id` from the database and the index, or fails with [`Error::NotFound`].
+    pub async fn remove_image(&self, post_id: PostId) -> Result<PostId> {
+        let mut data = self.state.write().await;
+        let image = self
+            .sql
+            .get_image(post_id)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("no post {}", post_id)))?;
+        // The row goes first, so a failed delete leaves the post searchable
+        let (_, advanced) = self.sql.remove_image(post_id).await?;
+        self.generation.fetch_add(advanced, Ordering::Relaxed);
+        data.remove_image(image.post_id, &image.s);
+        Ok(post_id)
+    }
+
+    /// Returns the `limit` best matches for `haar`, best match first.
+    ///
+    /// Scoring runs on the blocking thread pool under a read lock, so queries don't hold up
+    /// the async workers and run in parallel with each other.
+    pub async fn query(&self, haar: &HaarSignature, limit: usize) -> Result<Vec<SimValue>> {
+        let data = self.state.read().await;
+        let haar = haar.clone();
+        Ok(task::spawn_blocking(move || data.query_from_signature(&haar, limit)).await?)
+    }
+
+    /// Reports the load progress and statistics about the index and the database.
+    pub async fn status(&self) -> Result<Status> {
+        let data = self.state.read().await;
+        let index = task::spawn_blocking(move || data.stats()).await?;
+        Ok(Status {
+            load: self.progress.status(),
+            index,
+            storage: self.sql.storage_stats().await?,
         })
     }
 
-    pub async fn add_image(&self, haar: &HaarSignature) -> Option<u32> {
-        match self.sql.insert_signature(haar).await {
-            Some(id) => {
-                self.state
-                    .data
-                    .clone()
-                    .lock()
-                    .await.add_image_in_memory(id as imgdb::IqdbId, id as imgdb::PostId, haar)
-            }
-            None => None,
+    pub async fn get_signature(&self, post_id: PostId) -> Result<Option<HaarSignature>> {
+        Ok(self.sql.get_image(post_id).await?.map(|image| image.s))
+    }
+}
+
+/// Brings the index in line with a signature the database just stored.
+fn apply_upsert(
+    data: &mut ImgBin,
+    upsert: Upsert,
+    post_id: PostId,
+    haar: &HaarSignature,
+) -> AddStatus {
+    match upsert {
+        Upsert::Inserted(id) => {
+            data.add_image_in_memory(id, post_id, haar);
+            AddStatus::Inserted
+        }
+        Upsert::Updated(id, old) => {
+            data.remove_image(post_id, &old);
+            data.add_image_in_memory(id, post_id, haar);
+            AddStatus::Updated
+        }
+    }
+}
+
+/// Loads the snapshot at `path` with its generation, or returns `None` if it's missing,
+/// unreadable or stale.
+async fn load_snapshot(sql: &Sql, path: &Path) -> sqlx::Result<Option<(ImgBin, i64)>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let file = path.to_owned();
+    let loaded = task::spawn_blocking(move || snapshot::load(&file))
+        .await
+        .map_err(io::Error::other)
+        .and_then(|res| res);
+    let (img_bin, generation) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            warn!("could not load snapshot {}: {}", path.display(), e);
+            return Ok(None);
         }
+    };
+    let current = sql.generation().await?;
+    if generation != current {
+        info!(
+            "snapshot {} is stale (generation {}, database at {})",
+            path.display(),
+            generation,
+            current
+        );
+        return Ok(None);
     }
+    info!("loaded snapshot {}", path.display());
+    Ok(Some((img_bin, generation)))
+}
 
-    pub async fn remove_image(&self, post_id: imgdb::PostId) -> Option<imgdb::PostId> {
-        let image = self.sql.get_image(post_id).await;
-        if image.is_none() {
-            // add some logging ig
-            return None;
+/// Builds the index by streaming every row of the database, and returns it with the generation
+/// it reflects.
+async fn replay(sql: &Sql, progress: &LoadProgress) -> sqlx::Result<(ImgBin, i64)> {
+    // Read first, so rows changed during the replay make it look stale rather than current
+    let generation = sql.generation().await?;
+    let mut img_bin = ImgBin::new();
+    let mut sql_rows = sql.each_image();
+    while let Some(r) = sql_rows.try_next().await? {
+        trace!("the sqlite row was gotten: {}", r.id);
+        img_bin.add_image_in_memory(r.id, r.post_id, &r.s);
+        progress.loaded.fetch_add(1, Ordering::Relaxed);
+        if r.id % 250000 == 0 {
+            info!("loaded {} images", progress.loaded.load(Ordering::Relaxed));
         }
-        let image = image.unwrap();
-        self.state
-            .data
-            .clone()
-            .lock()
+    }
+    Ok((img_bin, generation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signature::haar::NUM_COEFS;
+
+    #[tokio::test]
+    async fn bad_signatures_are_not_stored() {
+        let db = std::env::temp_dir().join(format!("oiqdb-checked-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&db);
+        let mut config = Config::for_tests();
+        config.database.url = format!("sqlite://{}?mode=rwc", db.display());
+        let iqdb = IQDB::new(&config).await.unwrap();
+
+        let mut good = HaarSignature::new();
+        good.avglf = [0.5, 0.1, 0.1];
+        good.sig0.sig = [1; NUM_COEFS];
+        good.sig1.sig = [1; NUM_COEFS];
+        good.sig2.sig = [1; NUM_COEFS];
+        let mut bad = good.clone();
+        bad.sig2.sig[7] = i16::MIN;
+
+        let err = iqdb
+            .add_images(&[(1, good.clone()), (2, bad.clone())])
             .await
-            .remove_image(&image.s, image.id);
-        self.sql.remove_image(post_id).await.map_or(None, |_| Some(post_id))
+            .unwrap_err();
+        assert_eq!(err.kind(), "validation");
+        assert!(err.to_string().contains("post 2"), "{}", err);
+        assert_eq!(
+            iqdb.add_image(3, &bad).await.unwrap_err().kind(),
+            "validation"
+        );
+        assert_eq!(iqdb.sql.count_images().await.unwrap(), 0);
+
+        // Nothing is left behind that would fail the next load
+        iqdb.add_image(1, &good).await.unwrap();
+        let reloaded = IQDB::new(&config).await.unwrap();
+        assert_eq!(reloaded.query(&good, 5).await.unwrap().len(), 1);
+
+        std::fs::remove_file(&db).unwrap();
     }
 }
@@ -1,117 +1,135 @@
-use dotenvy::dotenv;
 use futures::stream::BoxStream;
-use futures::{FutureExt, Stream, TryStreamExt};
-use sqlx::sqlite::{SqliteQueryResult, SqliteRow};
+use serde::Serialize;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqliteQueryResult, SqliteRow};
 use sqlx::{Error, FromRow, Row, SqlitePool};
-use std::env;
-use std::env::VarError;
+use std::str::FromStr;
 
-use crate::signature;
+use crate::config::DatabaseConfig;
+use crate::iqdb::imgdb::{IqdbId, PostId};
 use crate::signature::HaarSignature;
 
+/// The SQLite database the signatures are stored in, which the index is built from.
 #[derive(Clone)]
 pub struct Sql {
     pool: SqlitePool,
 }
 
+/// The outcome of [`Sql::upsert_signature`].
+pub enum Upsert {
+    /// The post was new, and was stored under this IQDB ID.
+    Inserted(IqdbId),
+    /// The post was already stored under this IQDB ID, with the signature that was replaced.
+    Updated(IqdbId, Box<HaarSignature>),
+}
+
+/// Statistics about the database, see [`Sql::storage_stats`].
+#[derive(Debug, Serialize)]
+pub struct StorageStats {
+    /// Size of the main database file, from its page count.
+    pub file_bytes: i64,
+    /// Version of the latest applied migration.
+    pub schema_version: Option<i64>,
+}
+
+/// A stored signature, with the IQDB ID it's indexed under.
 pub struct SqlRow {
-    pub id: u32,
+    pub id: IqdbId,
+    pub post_id: PostId,
     pub s: HaarSignature,
 }
 
 impl FromRow<'_, SqliteRow> for SqlRow {
     fn from_row(row: &'_ SqliteRow) -> sqlx::Result<Self, Error> {
+        let avglf = [
+            row.try_get("avglf0")?,
+            row.try_get("avglf1")?,
+            row.try_get("avglf2")?,
+        ];
         Ok(Self {
-            id: row.try_get("id").unwrap(),
-            s: HaarSignature {
-                avglf: [
-                    row.try_get("avglf0")?,
-                    row.try_get("avglf1")?,
-                    row.try_get("avglf2")?,
-                ],
-                sig0: serde_json::from_slice(row.try_get("sig0")?).unwrap(), // TODO: dont like the use of unwrap here
-                sig1: serde_json::from_slice(row.try_get("sig1")?).unwrap(),
-                sig2: serde_json::from_slice(row.try_get("sig2")?).unwrap(),
-            },
+            id: row.try_get("id")?,
+            post_id: row.try_get("post_id")?,
+            s: HaarSignature::from_bytes(avglf, row.try_get("sig")?).map_err(|e| {
+                Error::ColumnDecode {
+                    index: "sig".to_string(),
+                    source: Box::new(e),
+                }
+            })?,
         })
     }
 }
 
 impl Sql {
-    pub async fn new() -> Self {
-        match get_db_url().await {
-            Ok(url) => Sql {
-                pool: initialize_and_connect_storage(url.as_str())
-                    .await
-                    .expect("Error while initializing and connecting to database"),
-            },
-            Err(err) => panic!("DB environment variable issue, {}", err),
+    /// Connects to the database described by `config` and runs any pending migrations.
+    pub async fn connect(config: &DatabaseConfig) -> sqlx::Result<Self> {
+        let mut options = SqliteConnectOptions::from_str(&config.url)?;
+        for (name, value) in config.pragmas.iter() {
+            options = options.pragma(name.clone(), value.clone());
         }
+        Ok(Sql {
+            pool: initialize_and_connect_storage(options).await?,
+        })
     }
 
-    pub async fn insert_signature(&self, signature: &HaarSignature) -> Option<i64> {
-        match self.pool.acquire().await {
-            Ok(mut conn) => {
-                let blob0 = serde_json::to_vec(&signature.sig0).unwrap();
-                let blob1 = serde_json::to_vec(&signature.sig1).unwrap();
-                let blob2 = serde_json::to_vec(&signature.sig2).unwrap();
-
-                sqlx::query!(
-                    r#"
-                INSERT INTO images ( avglf0, avglf1, avglf2, sig0, sig1, sig2 )
-                VALUES ( ($1), ($2), ($3), ($4), ($5), ($6))
-                "#,
-                    signature.avglf[0], // TODO: looks like some possible issues with this, REAL is f64
-                    signature.avglf[1],
-                    signature.avglf[2],
-                    blob0,
-                    blob1,
-                    blob2
-                )
-                .execute(&mut *conn)
-                .await
-                .map_or(None, |query_result| Some(query_result.last_insert_rowid()))
-            }
-            Err(_) => None,
+    /// Returns the number of stored images.
+    pub async fn count_images(&self) -> sqlx::Result<i64> {
+        sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count: i64" FROM images"#)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    /// Stores `signature` for `post_id` in a single transaction, and returns how far that moved
+    /// the [generation](Sql::generation).
+    ///
+    /// If the post already has a signature, it's replaced in place and keeps its IQDB ID.
+    pub async fn upsert_signature(
+        &self,
+        post_id: PostId,
+        signature: &HaarSignature,
+    ) -> sqlx::Result<(Upsert, i64)> {
+        let mut tx = self.pool.begin().await?;
+        let before = generation_in(&mut tx).await?;
+        let upsert = upsert_in(&mut tx, post_id, signature).await?;
+        let after = generation_in(&mut tx).await?;
+        tx.commit().await?;
+        Ok((upsert, after - before))
+    }
+
+    /// Stores every signature of `images` in a single transaction, in order, so either all of
+    /// them are stored or none are. See [`Sql::upsert_signature`].
+    pub async fn upsert_signatures(
+        &self,
+        images: &[(PostId, HaarSignature)],
+    ) -> sqlx::Result<(Vec<Upsert>, i64)> {
+        let mut tx = self.pool.begin().await?;
+        let before = generation_in(&mut tx).await?;
+        let mut upserts = Vec::with_capacity(images.len());
+        for (post_id, signature) in images {
+            upserts.push(upsert_in(&mut tx, *post_id, signature).await?);
         }
+        let after = generation_in(&mut tx).await?;
+        tx.commit().await?;
+        Ok((upserts, after - before))
     }
 
-    pub async fn get_image(&self, id: u32) -> Option<SqlRow> {
+    /// Returns the stored signature of `post_id`, if it has one.
+    pub async fn get_image(&self, post_id: PostId) -> sqlx::Result<Option<SqlRow>> {
         sqlx::query_as(
             r#"
-            SELECT id, avglf0, avglf1, avglf2, sig0, sig1, sig2
+            SELECT id, post_id, avglf0, avglf1, avglf2, sig
             FROM images
-            WHERE id = (?)
+            WHERE post_id = (?)
             "#,
         )
-        .bind(id)
+        .bind(post_id)
         .fetch_optional(&self.pool)
         .await
-        .unwrap_or(None)
     }
 
-    pub async fn list_rows(&self) -> Option<i64> {
-        sqlx::query!(
-            r#"
-            SELECT id, avglf0, avglf1, avglf2, sig0, sig1, sig2
-            FROM images
-            ORDER BY id ASC
-            "#,
-        )
-        .fetch_all(&self.pool)
-        .await
-        .map_or(None, |rows| {
-            for r in rows {
-                println!("- [{}]: {} {} {}", r.id, &r.avglf0, &r.avglf1, &r.avglf2,);
-            }
-            Some(1)
-        })
-    }
-
-    pub fn each_image(&self) -> BoxStream<sqlx::Result<SqlRow>> {
+    /// Streams every stored signature, by IQDB ID.
+    pub fn each_image(&self) -> BoxStream<'_, sqlx::Result<SqlRow>> {
         sqlx::query_as(
             r#"
-            SELECT id, avglf0, avglf1, avglf2, sig0, sig1, sig2
+            SELECT id, post_id, avglf0, avglf1, avglf2, sig
             FROM images
             ORDER BY id ASC
             "#,
@@ -119,44 +137,136 @@ impl Sql {
         .fetch(&self.pool)
     }
 
-    pub async fn remove_image(&self, id: u32) -> Result<SqliteQueryResult, Error> {
+    /// Returns the size of the database and the version of its schema.
+    pub async fn storage_stats(&self) -> sqlx::Result<StorageStats> {
+        let page_count: i64 = sqlx::query_scalar("PRAGMA page_count")
+            .fetch_one(&self.pool)
+            .await?;
+        let page_size: i64 = sqlx::query_scalar("PRAGMA page_size")
+            .fetch_one(&self.pool)
+            .await?;
+        let schema_version: Option<i64> =
+            sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success")
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(StorageStats {
+            file_bytes: page_count * page_size,
+            schema_version,
+        })
+    }
+
+    /// Returns the number of changes made to the images table so far, which an index snapshot
+    /// compares against to tell if it's stale.
+    pub async fn generation(&self) -> sqlx::Result<i64> {
         let mut conn = self.pool.acquire().await?;
-        sqlx::query!(
+        generation_in(&mut conn).await
+    }
+
+    /// Deletes the signature of `post_id`, and returns how far that moved the
+    /// [generation](Sql::generation). Deleting a post that isn't stored does nothing.
+    pub async fn remove_image(&self, post_id: PostId) -> Result<(SqliteQueryResult, i64), Error> {
+        let mut tx = self.pool.begin().await?;
+        let before = generation_in(&mut tx).await?;
+        let result = sqlx::query!(
             r#"
             DELETE FROM images
-            WHERE id = ($1)
+            WHERE post_id = ($1)
             "#,
-            id
+            post_id
         )
-        .execute(&mut *conn)
-        .await
+        .execute(&mut *tx)
+        .await?;
+        let after = generation_in(&mut tx).await?;
+        tx.commit().await?;
+        Ok((result, after - before))
     }
 }
 
-async fn get_db_url() -> Result<String, VarError> {
-    dotenv().expect("Environment variable dotfile not found by dotenv.");
-    env::var("DATABASE_URL")
+async fn generation_in(conn: &mut SqliteConnection) -> sqlx::Result<i64> {
+    sqlx::query_scalar!("SELECT generation FROM index_generation WHERE id = 0")
+        .fetch_one(&mut *conn)
+        .await
 }
 
-async fn initialize_and_connect_storage(url: &str) -> Option<SqlitePool> {
-    match SqlitePool::connect(url).await {
-        Ok(pool) => run_migrations(pool).await,
-        Err(_) => None,
-    }
+/// Stores `signature` for `post_id` within the transaction on `conn`.
+async fn upsert_in(
+    conn: &mut SqliteConnection,
+    post_id: PostId,
+    signature: &HaarSignature,
+) -> sqlx::Result<Upsert> {
+    let old: Option<SqlRow> = sqlx::query_as(
+        r#"
+        SELECT id, post_id, avglf0, avglf1, avglf2, sig
+        FROM images
+        WHERE post_id = (?)
+        "#,
+    )
+    .bind(post_id)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    let blob = signature.sig_to_bytes();
+
+    let upsert = match old {
+        Some(old) => {
+            sqlx::query!(
+                r#"
+            UPDATE images
+            SET avglf0 = ($1), avglf1 = ($2), avglf2 = ($3), sig = ($4)
+            WHERE id = ($5)
+            "#,
+                signature.avglf[0],
+                signature.avglf[1],
+                signature.avglf[2],
+                blob,
+                old.id
+            )
+            .execute(&mut *conn)
+            .await?;
+            Upsert::Updated(old.id, Box::new(old.s))
+        }
+        None => {
+            let query_result = sqlx::query!(
+                r#"
+            INSERT INTO images ( post_id, avglf0, avglf1, avglf2, sig )
+            VALUES ( ($1), ($2), ($3), ($4), ($5))
+            "#,
+                post_id,
+                signature.avglf[0],
+                signature.avglf[1],
+                signature.avglf[2],
+                blob
+            )
+            .execute(&mut *conn)
+            .await?;
+            Upsert::Inserted(query_result.last_insert_rowid() as IqdbId)
+        }
+    };
+
+    Ok(upsert)
 }
 
-async fn run_migrations(pool: SqlitePool) -> Option<SqlitePool> {
-    match sqlx::migrate!().run(&pool).await {
-        Ok(_) => Some(pool),
-        Err(_) => None,
-    }
+async fn initialize_and_connect_storage(options: SqliteConnectOptions) -> sqlx::Result<SqlitePool> {
+    let pool = SqlitePool::connect_with(options).await?;
+    run_migrations(&pool).await?;
+    Ok(pool)
+}
+
+async fn run_migrations(pool: &SqlitePool) -> sqlx::Result<()> {
+    sqlx::migrate!().run(pool).await?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::signature;
     use crate::signature::haar;
+    use dotenvy::dotenv;
     use regex::Regex;
+    use sqlx::sqlite::SqlitePoolOptions;
+    use sqlx::Executor;
+    use std::env;
     use std::path::Path;
 
     #[tokio::test]
@@ -168,7 +278,7 @@ mod tests {
         let url = env::var("DATABASE_URL").unwrap();
 
         let sql = Sql {
-            pool: initialize_and_connect_storage(url.as_str())
+            pool: initialize_and_connect_storage(url.parse().unwrap())
                 .await
                 .expect("Error while initializing and connecting to database."),
         };
@@ -185,37 +295,125 @@ mod tests {
 
         // Insert a signature
         let sig = signature::HaarSignature {
-            // Create a blank haar signature to insert
+            // Create a plain haar signature to insert
             avglf: [0.0, 0.0, 0.0],
             sig0: haar::SigT {
-                sig: [0; haar::NUM_COEFS],
+                sig: [1; haar::NUM_COEFS],
             },
             sig1: haar::SigT {
-                sig: [0; haar::NUM_COEFS],
+                sig: [1; haar::NUM_COEFS],
             },
             sig2: haar::SigT {
-                sig: [0; haar::NUM_COEFS],
+                sig: [1; haar::NUM_COEFS],
             },
         };
 
-        let id = sql
-            .insert_signature(&sig)
-            .await
-            .expect("Error while inserting signature.");
-        println!("Added new entry with id {id}.");
-        let _ = sql.list_rows().await.expect("Error while listing rows");
+        let post_id: PostId = 999_999;
+        let _ = sql.remove_image(post_id).await;
+        let Ok((Upsert::Inserted(id), 1)) = sql.upsert_signature(post_id, &sig).await else {
+            panic!("Error while inserting signature.");
+        };
+        println!("Added new entry with id {id} for post {post_id}.");
+        assert!(sql.count_images().await.unwrap() >= 1);
+
+        // Re-adding a post replaces its signature and keeps its id
+        let mut replacement = sig.clone();
+        replacement.avglf = [0.5, 0.25, 0.125];
+        let Ok((Upsert::Updated(updated_id, old), 1)) =
+            sql.upsert_signature(post_id, &replacement).await
+        else {
+            panic!("Error while replacing signature.");
+        };
+        assert_eq!(updated_id, id);
+        assert_eq!(old.avglf, sig.avglf);
 
-        let img = sql.get_image(id as u32).await.unwrap();
+        let img = sql.get_image(post_id).await.unwrap().unwrap();
+        assert_eq!(img.id, id);
+        assert_eq!(img.post_id, post_id);
+        assert_eq!(img.s.avglf, replacement.avglf);
+        let generation = sql.generation().await.unwrap();
         println!("For id: {id}, the SqlRow's HaarSignature is: {:?}", img.s);
 
         // Remove image
-        println!("Running remove image for id: {id}");
-        let _ = sql
-            .remove_image(id as u32)
+        println!("Running remove image for post: {post_id}");
+        let (_, advanced) = sql
+            .remove_image(post_id)
             .await
-            .expect("Error while removing id: {id}");
-        let _ = sql.list_rows().await.expect("Error while listing rows");
+            .expect("Error while removing post: {post_id}");
+        assert_eq!(advanced, 1);
+        assert!(sql.get_image(post_id).await.unwrap().is_none());
+        assert!(sql.generation().await.unwrap() > generation);
+
+        let stats = sql.storage_stats().await.unwrap();
+        assert!(stats.file_bytes > 0);
+        assert_eq!(stats.schema_version, Some(4));
 
         sql.pool.close().await;
     }
+
+    #[tokio::test]
+    async fn migrate_json_signatures() {
+        // Runs against a private in-memory database, so it doesn't touch the one above
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../migrations/1_setup.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../migrations/2_post_id.sql"))
+            .await
+            .unwrap();
+
+        let mut coefs: [i16; haar::NUM_COEFS] = [7; haar::NUM_COEFS];
+        coefs[0] = -16383;
+        coefs[1] = -1;
+        coefs[39] = 300;
+        let sig = haar::SigT { sig: coefs };
+        let other = haar::SigT {
+            sig: [-5; haar::NUM_COEFS],
+        };
+        sqlx::query(
+            r#"
+            INSERT INTO images ( post_id, avglf0, avglf1, avglf2, sig0, sig1, sig2 )
+            VALUES ( 42, 0.5, 0.25, 0.125, (?), (?), (?) )
+            "#,
+        )
+        .bind(serde_json::to_vec(&sig).unwrap())
+        .bind(serde_json::to_vec(&other).unwrap())
+        .bind(serde_json::to_vec(&sig).unwrap())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        pool.execute(include_str!("../../migrations/3_binary_signatures.sql"))
+            .await
+            .unwrap();
+
+        let row: SqlRow =
+            sqlx::query_as("SELECT id, post_id, avglf0, avglf1, avglf2, sig FROM images")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(row.post_id, 42);
+        assert_eq!(row.s.avglf, [0.5, 0.25, 0.125]);
+        assert_eq!(row.s.sig0.sig, coefs);
+        assert_eq!(row.s.sig1.sig, other.sig);
+        assert_eq!(row.s.sig2.sig, coefs);
+
+        // Bad blobs are reported as decode errors
+        sqlx::query("UPDATE images SET sig = X'02'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let err = sqlx::query_as::<_, SqlRow>(
+            "SELECT id, post_id, avglf0, avglf1, avglf2, sig FROM images",
+        )
+        .fetch_one(&pool)
+        .await
+        .err()
+        .unwrap();
+        assert!(matches!(err, Error::ColumnDecode { .. }));
+    }
 }
@@ -1,24 +1,30 @@
-use crate::signature::haar::{Idx, NUM_COEFS, NUM_PIXELS, NUM_PIXELS_SQUARED};
+use crate::signature::haar::{NUM_COEFS, NUM_PIXELS, NUM_PIXELS_SQUARED};
 use crate::signature::{haar, HaarSignature};
+use bitvec::vec::BitVec;
 use image::DynamicImage;
 use num_traits::abs;
-use std::borrow::Borrow;
+use serde::Serialize;
 use std::cmp::{max, min, Ordering};
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, HashMap};
 use std::default::Default;
+use std::mem::size_of;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+
+use crate::error::{Error, Result};
+use crate::iqdb::snapshot::Mapped;
+use crate::metrics::{Stage, METRICS};
 
 pub type ImageId = u32;
 pub type IqdbId = u32; // An internal IQDB image ID.
 pub type PostId = u32; // An external (booru) post ID.
 type Score = f32;
-type SimVector = Vec<SimValue>;
-type Sig = [Idx; NUM_COEFS];
 type Bucket = Vec<u32>;
 
 const N_SIGNS: usize = 2; // 2 haar coefficient signs (positive and negative)
 const N_INDEXES: usize = NUM_PIXELS_SQUARED; // 16384 haar matrix indices
+pub const N_BUCKETS: usize = haar::N_COLORS * N_SIGNS * N_INDEXES; // 98304 buckets in total
 
 // Weights for the Haar coefficients, straight from the referenced paper:
 pub const WEIGHTS: [&[f32; 3]; 6] = [
@@ -48,7 +54,9 @@ impl Default for LuminNative {
     }
 }
 
-struct SimValue {
+/// A single query result: the matching image's ID and its similarity score.
+#[derive(Debug)]
+pub struct SimValue {
     pub id: ImageId,
     pub score: Score,
 }
@@ -58,13 +66,13 @@ impl Eq for SimValue {}
 impl Ord for SimValue {
     fn cmp(&self, other: &Self) -> Ordering {
         // return score < other.score
-        self.score.partial_cmp(&other.score).unwrap()
+        self.score.total_cmp(&other.score)
     }
 }
 
 impl PartialOrd for SimValue {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.score.partial_cmp(&other.score)
+        Some(self.cmp(other))
     }
 }
 
@@ -74,11 +82,63 @@ impl PartialEq for SimValue {
     }
 }
 
+/// Statistics about an [`ImgBin`], see [`ImgBin::stats`].
+#[derive(Debug, Serialize)]
+pub struct IndexStats {
+    pub images: usize,
+    /// Info slots below `info_used` that hold no image, which queries still scan.
+    pub deleted: usize,
+    pub info_capacity: usize,
+    /// Slots up to the highest one that was ever assigned.
+    pub info_used: usize,
+    pub postings: usize,
+    /// Postings kept in memory on top of the mapped snapshot.
+    pub delta_postings: usize,
+    /// The largest bucket of each color and sign.
+    pub largest_buckets: Vec<BucketStats>,
+    /// Approximate heap memory of the index, not counting the mapped snapshot.
+    pub memory_bytes: usize,
+    pub mapped_bytes: usize,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct BucketStats {
+    pub color: usize,
+    pub sign: char,
+    pub index: usize,
+    pub postings: usize,
+}
+
+/// Shared handle to the index. Queries only need a read lock, so any number of them run in
+/// parallel, while adds and removes take the write lock.
 #[derive(Clone)]
-pub struct ImgBinState {
-    pub data: Arc<Mutex<ImgBin>>,
+pub(crate) struct ImgBinState {
+    pub data: Arc<RwLock<ImgBin>>,
 }
 
+impl ImgBinState {
+    /// Takes the read lock, recording how long it waited.
+    pub async fn read(&self) -> OwnedRwLockReadGuard<ImgBin> {
+        let start = Instant::now();
+        let guard = self.data.clone().read_owned().await;
+        METRICS.lock_waited("read", start.elapsed());
+        guard
+    }
+
+    /// Takes the write lock, recording how long it waited.
+    pub async fn write(&self) -> OwnedRwLockWriteGuard<ImgBin> {
+        let start = Instant::now();
+        let guard = self.data.clone().write_owned().await;
+        METRICS.lock_waited("write", start.elapsed());
+        guard
+    }
+}
+
+/// The in-memory index of image signatures, which answers similarity queries.
+///
+/// Every signature coefficient has a bucket listing the images that share it, so a query only
+/// visits the images that have something in common with it. Images are numbered by an internal
+/// [`IqdbId`], and reported by their [`PostId`].
 pub struct ImgBin {
     // A 128x128 weight mask matrix, where M[x][y] = min(max(x, y), 5). Used in score calculation.
     // 0 1 2 3 4 5 5 ...
@@ -91,14 +151,32 @@ pub struct ImgBin {
     // . . . . . . .
     // . . . . . . .
     // . . . . . . .
-    bin: [usize; NUM_PIXELS * NUM_PIXELS],
+    // Kept on the heap, since an ImgBin is moved around by value while it's loaded.
+    bin: Vec<usize>,
+    // Bucket lists loaded from a snapshot, read straight from the mapped file.
+    base: Option<Mapped>,
+    // Marks the IQDB IDs whose postings in `base` are out of date, since they were removed or
+    // replaced after the snapshot was taken.
+    masked: BitVec,
+    // Postings added since the snapshot, or all of them if the index wasn't loaded from one.
     buckets: Vec<Vec<Vec<Bucket>>>,
     info: Vec<ImageInfo>,
+    // Maps the external post IDs to the internal IQDB IDs used by the buckets and info vector.
+    post_ids: HashMap<PostId, IqdbId>,
+    // One past the highest IQDB ID assigned so far.
+    next_id: IqdbId,
+}
+
+impl Default for ImgBin {
+    fn default() -> Self {
+        ImgBin::new()
+    }
 }
 
 impl ImgBin {
+    /// Creates an empty index.
     pub fn new() -> Self {
-        let mut bin: [usize; NUM_PIXELS * NUM_PIXELS] = [0; NUM_PIXELS * NUM_PIXELS];
+        let mut bin: Vec<usize> = vec![0; NUM_PIXELS * NUM_PIXELS];
         let mut i = 0;
         let mut j: usize;
         while i < NUM_PIXELS {
@@ -111,30 +189,110 @@ impl ImgBin {
         }
         Self {
             bin,
+            base: None,
+            masked: BitVec::new(),
             buckets: vec![vec![vec![Vec::new(); N_INDEXES]; N_SIGNS]; haar::N_COLORS], // 3 * 2 * 16384 = 98304 total buckets
             info: Vec::new(),
+            post_ids: HashMap::new(),
+            next_id: 0,
         }
     }
 
-    pub fn add(&mut self, sig: &HaarSignature, iqdb_id: u32) {
+    /// Adds `haar` for `post_id` under the next free IQDB ID, and returns that ID.
+    ///
+    /// Fails with [`Error::Conflict`] if the post is already in the index. Its old signature has
+    /// to be removed with [`ImgBin::remove_image`] first, since the index doesn't keep it.
+    pub fn add_image(&mut self, post_id: PostId, haar: &HaarSignature) -> Result<IqdbId> {
+        if self.post_ids.contains_key(&post_id) {
+            return Err(Error::Conflict(format!(
+                "post {} is already indexed",
+                post_id
+            )));
+        }
+        let iqdb_id = self.next_id;
+        self.add_image_in_memory(iqdb_id, post_id, haar);
+        Ok(iqdb_id)
+    }
+
+    pub(crate) fn add(&mut self, sig: &HaarSignature, iqdb_id: u32) {
         self.each_bucket(sig, |bucket: &mut Bucket| bucket.push(iqdb_id));
     }
 
-    /// Removes a signature from the buckets and from the info vector.
+    /// Removes a post's signature from the buckets and from the info vector. `sig` has to be the
+    /// signature the post was added with.
     ///
-    /// Serves as a helper function for crate::iqdb::IQDB, since this sort of memory access is easier in C/C++.
-    pub fn remove_image(&mut self, sig: &HaarSignature, iqdb_id: u32) {
+    /// Returns the IQDB ID the post was stored under, or `None` if the post isn't in memory.
+    pub fn remove_image(&mut self, post_id: PostId, sig: &HaarSignature) -> Option<IqdbId> {
+        let iqdb_id = self.post_ids.remove(&post_id)?;
         self.remove(sig, iqdb_id);
         self.info[iqdb_id as usize].avgl.v[0] = 0.0;
+        Some(iqdb_id)
     }
 
-    pub fn remove(&mut self, sig: &HaarSignature, iqdb_id: u32) {
+    pub(crate) fn remove(&mut self, sig: &HaarSignature, iqdb_id: u32) {
         self.each_bucket(sig, |bucket: &mut Bucket| {
             bucket.retain(|&x: &u32| x != iqdb_id)
-        })
+        });
+        // The mapped postings can't be changed, so they're skipped from now on instead
+        if (iqdb_id as usize) < self.masked.len() {
+            self.masked.set(iqdb_id as usize, true);
+        }
+    }
+
+    /// Builds an index over the bucket lists of a mapped snapshot, without copying them.
+    ///
+    /// Only the info vector is read into memory. Later adds go to the in-memory buckets, and
+    /// postings of removed or replaced images in the snapshot are masked out.
+    pub(crate) fn from_mapped(mapped: Mapped) -> Self {
+        let mut img_bin = ImgBin::new();
+        for (iqdb_id, (id, v)) in mapped.info().enumerate() {
+            img_bin.info.push(ImageInfo {
+                id,
+                avgl: LuminNative { v },
+            });
+            if !img_bin.is_deleted(iqdb_id as IqdbId) {
+                img_bin.post_ids.insert(id, iqdb_id as IqdbId);
+            }
+        }
+        img_bin.masked = BitVec::repeat(false, img_bin.info.len());
+        img_bin.next_id = img_bin.info_used() as IqdbId;
+        img_bin.base = Some(mapped);
+        img_bin
+    }
+
+    /// Returns the post ID and averages of every slot of the info vector, by IQDB ID.
+    pub(crate) fn info_entries(&self) -> impl Iterator<Item = (PostId, [Score; 3])> + '_ {
+        self.info.iter().map(|info| (info.id, info.avgl.v))
+    }
+
+    /// Returns the current postings of the bucket at flat index `b`, over both layers.
+    ///
+    /// Buckets are numbered by color, then sign, then coefficient index.
+    pub(crate) fn postings(&self, b: usize) -> impl Iterator<Item = IqdbId> + '_ {
+        let delta =
+            &self.buckets[b / (N_SIGNS * N_INDEXES)][(b / N_INDEXES) % N_SIGNS][b % N_INDEXES];
+        self.base_postings(b).chain(delta.iter().copied())
     }
 
-    fn at(&mut self, color: usize, coef: i16) -> &mut Bucket {
+    /// Returns the unmasked postings of the mapped bucket at flat index `b`.
+    fn base_postings(&self, b: usize) -> impl Iterator<Item = IqdbId> + '_ {
+        let base: &[IqdbId] = self.base.as_ref().map_or(&[], |base| base.bucket(b));
+        base.iter()
+            .copied()
+            .filter(|&iqdb_id| !self.masked[iqdb_id as usize])
+    }
+
+    fn at(&self, color: usize, coef: i16) -> &Bucket {
+        let sign: bool = coef < 0;
+        &self.buckets[color][sign as usize][abs(coef) as usize]
+    }
+
+    fn bucket_index(color: usize, coef: i16) -> usize {
+        let sign: bool = coef < 0;
+        (color * N_SIGNS + sign as usize) * N_INDEXES + abs(coef) as usize
+    }
+
+    fn at_mut(&mut self, color: usize, coef: i16) -> &mut Bucket {
         let sign: bool = coef < 0;
         &mut self.buckets[color][sign as usize][abs(coef) as usize]
     }
@@ -146,13 +304,19 @@ impl ImgBin {
         for c in 0..sig.num_colors() {
             for i in 0..NUM_COEFS {
                 let coef: i16 = sig[c][i];
-                let bucket: &mut Bucket = self.at(c, coef);
+                let bucket: &mut Bucket = self.at_mut(c, coef);
                 func(bucket)
             }
         }
     }
 
-    pub fn add_image_in_memory(&mut self, iqdb_id: IqdbId, post_id: PostId, haar: &HaarSignature) -> Option<IqdbId> {
+    /// Adds `haar` for `post_id` under `iqdb_id`, which the database assigned.
+    pub(crate) fn add_image_in_memory(
+        &mut self,
+        iqdb_id: IqdbId,
+        post_id: PostId,
+        haar: &HaarSignature,
+    ) -> Option<IqdbId> {
         if iqdb_id >= self.info.len() as u32 {
             // Growing info vec
             let resize = (iqdb_id + 5000) as usize;
@@ -162,58 +326,173 @@ impl ImgBin {
         self.info[iqdb_id as usize] = ImageInfo {
             id: post_id,
             avgl: LuminNative {
-                v: haar.avglf,
+                v: haar.avglf.map(|v| v as Score),
             },
         };
+        self.post_ids.insert(post_id, iqdb_id);
+        self.next_id = max(self.next_id, iqdb_id + 1);
         Some(iqdb_id)
     }
 
+    /// Counts the images, info slots and postings, and estimates the memory in use.
+    ///
+    /// Walks every bucket, so it takes about as long as a query.
+    pub fn stats(&self) -> IndexStats {
+        let info_used = self.info_used();
+
+        let mut postings = 0;
+        let mut delta_postings = 0;
+        let mut largest_buckets: Vec<BucketStats> = Vec::new();
+        for color in 0..haar::N_COLORS {
+            for sign in 0..N_SIGNS {
+                let mut largest = BucketStats {
+                    color,
+                    sign: if sign == 0 { '+' } else { '-' },
+                    index: 0,
+                    postings: 0,
+                };
+                for index in 0..N_INDEXES {
+                    let count = self
+                        .postings((color * N_SIGNS + sign) * N_INDEXES + index)
+                        .count();
+                    postings += count;
+                    delta_postings += self.buckets[color][sign][index].len();
+                    if count > largest.postings {
+                        largest.index = index;
+                        largest.postings = count;
+                    }
+                }
+                largest_buckets.push(largest);
+            }
+        }
+
+        let delta_bytes: usize = self
+            .buckets
+            .iter()
+            .flatten()
+            .flatten()
+            .map(|bucket| size_of::<Bucket>() + bucket.capacity() * size_of::<IqdbId>())
+            .sum();
+        let memory_bytes = self.bin.capacity() * size_of::<usize>()
+            + self.info.capacity() * size_of::<ImageInfo>()
+            + self.post_ids.capacity() * (size_of::<PostId>() + size_of::<IqdbId>() + 1)
+            + self.masked.capacity() / 8
+            + delta_bytes;
+
+        IndexStats {
+            images: self.post_ids.len(),
+            deleted: (0..info_used)
+                .filter(|&i| self.is_deleted(i as IqdbId))
+                .count(),
+            info_capacity: self.info.len(),
+            info_used,
+            postings,
+            delta_postings,
+            largest_buckets,
+            memory_bytes,
+            mapped_bytes: self.base.as_ref().map_or(0, |base| base.file_len()),
+        }
+    }
+
+    // Counts the info slots up to the highest one that holds or held an image.
+    fn info_used(&self) -> usize {
+        self.info
+            .iter()
+            .rposition(|info| info.id != 0 || info.avgl.v[0] != 0.0)
+            .map_or(0, |i| i + 1)
+    }
+
     fn is_deleted(&self, iqdb_id: IqdbId) -> bool {
-        return self.info[iqdb_id as usize].avgl.v[0] == 0.0;
+        self.info[iqdb_id as usize].avgl.v[0] == 0.0
     }
 
-    fn query_from_blob(&self, image: DynamicImage, limit: i32) {
+    /// Computes the signature of `image` and returns the `num_res` most similar images.
+    pub fn query_from_blob(&self, image: DynamicImage, num_res: usize) -> Vec<SimValue> {
         let signature: HaarSignature = HaarSignature::from(image);
+        self.query_from_signature(&signature, num_res)
     }
 
-    fn query_from_signature(&mut self, signature: &HaarSignature, num_res: usize) {
+    /// Returns up to `num_res` matches for `signature`, best match first.
+    ///
+    /// Each result holds the post ID of the match and a similarity score, normalized the same
+    /// way as the C++ iqdb, where an identical signature scores 100.
+    pub fn query_from_signature(&self, signature: &HaarSignature, num_res: usize) -> Vec<SimValue> {
+        let timer = METRICS.time(Stage::Scoring);
         let mut scores: Vec<Score> = vec![0.0; self.info.len()];
         // Luminance score (DC coefficient)
-        for i in 0..scores.len() {
-            let image_info: &ImageInfo = &self.info[i];
+        for (image_info, score) in self.info.iter().zip(scores.iter_mut()) {
             let mut s: Score = 0.0;
 
-            for c in 0..signature.num_colors() {
-                s += WEIGHTS[0][c] * abs(image_info.avgl.v[c] - signature.avglf[c]);
+            for (c, weight) in WEIGHTS[0].iter().enumerate().take(signature.num_colors()) {
+                s += weight * abs(image_info.avgl.v[c] - signature.avglf[c] as Score);
             }
 
-            scores[i] = s;
+            *score = s;
         }
 
         let mut scale: Score = 0.0;
-        for c in 0..signature.num_colors() {
-            for b in 0..NUM_COEFS { // for every coef on a sig
+        for b in 0..NUM_COEFS {
+            // for every coef on a sig
+            for c in 0..signature.num_colors() {
                 let coef: i16 = signature[c][b];
-                let w: usize = self.bin[abs(coef) as usize].clone(); // we need to clone the usize to release it here
-                let bucket: &mut Bucket = self.at(c, coef);
-                if bucket.is_empty() {
-                    continue;
-                }
+                // Weight is based on how far the index is from the [0,0] corner
+                let w: usize = self.bin[abs(coef) as usize];
                 let weight: Score = WEIGHTS[w][c];
-                scale -= weight;
-                for index in bucket.iter() {
-                    scores[*index as usize] -= weight;
+                let mut hit = false;
+                let postings = self
+                    .base_postings(Self::bucket_index(c, coef))
+                    .chain(self.at(c, coef).iter().copied());
+                for index in postings {
+                    scores[index as usize] -= weight;
+                    hit = true;
+                }
+                // Only buckets with postings count towards the scale
+                if hit {
+                    scale -= weight;
                 }
             }
         }
 
+        drop(timer);
+
         // Fill up the numres-bounded priority queue (largest at top):
-        let mut i: IqdbId = 0;
-        let pq_results: BinaryHeap<SimValue> = BinaryHeap::new();
-        while (pq_results.len() < num_res) && (i < scores.len() as u32) {
-            if !self.is_deleted(i) {}
+        let _timer = METRICS.time(Stage::TopK);
+        let mut i: usize = 0;
+        let mut pq: BinaryHeap<SimValue> = BinaryHeap::with_capacity(num_res);
+        while (pq.len() < num_res) && (i < scores.len()) {
+            if !self.is_deleted(i as IqdbId) {
+                pq.push(SimValue {
+                    id: i as ImageId,
+                    score: scores[i],
+                });
+            }
             i += 1;
         }
+
+        // Replace the worst queued match whenever a better one turns up:
+        while i < scores.len() {
+            if !self.is_deleted(i as IqdbId) && pq.peek().is_some_and(|top| scores[i] < top.score) {
+                pq.pop();
+                pq.push(SimValue {
+                    id: i as ImageId,
+                    score: scores[i],
+                });
+            }
+            i += 1;
+        }
+
+        // Lowest raw score is the best match, so the ascending order is the result order.
+        pq.into_sorted_vec()
+            .into_iter()
+            .map(|mut res| {
+                if scale != 0.0 {
+                    res.score /= scale;
+                }
+                res.score *= 100.0;
+                res.id = self.info[res.id as usize].id;
+                res
+            })
+            .collect()
     }
 }
 
@@ -221,9 +500,188 @@ impl ImgBin {
 mod tests {
     use super::*;
     use crate::iqdb::imgdb::ImgBin;
+    use crate::iqdb::snapshot;
+    use crate::signature::haar::SigT;
+
+    fn signature(avglf: haar::Lumin, offset: i16) -> HaarSignature {
+        let mut sig: [i16; NUM_COEFS] = [0; NUM_COEFS];
+        for (i, s) in sig.iter_mut().enumerate() {
+            *s = offset + i as i16 + 1;
+        }
+        HaarSignature {
+            avglf,
+            sig0: SigT { sig },
+            sig1: SigT { sig },
+            sig2: SigT { sig },
+        }
+    }
 
     #[test]
     fn max_paths() {
         let img_bin: ImgBin = ImgBin::new();
+        assert_eq!(img_bin.bin[0], 0);
+        assert_eq!(img_bin.bin[NUM_PIXELS + 1], 1);
+        assert_eq!(img_bin.bin[NUM_PIXELS_SQUARED - 1], 5);
+    }
+
+    #[test]
+    fn query_ranks_matches() {
+        let mut img_bin: ImgBin = ImgBin::new();
+        let a = signature([0.5, 0.1, 0.1], 0);
+        let b = signature([0.4, 0.2, 0.1], 20);
+        let c = signature([0.1, 0.3, 0.2], 1000);
+        img_bin.add_image_in_memory(1, 100, &a);
+        img_bin.add_image_in_memory(2, 200, &b);
+        img_bin.add_image_in_memory(3, 300, &c);
+
+        let res = img_bin.query_from_signature(&a, 10);
+        assert_eq!(res.len(), 3);
+        assert_eq!(
+            res.iter().map(|r| r.id).collect::<Vec<_>>(),
+            [100, 200, 300]
+        );
+        assert!((res[0].score - 100.0).abs() < 1e-3);
+        assert!(res[0].score > res[1].score && res[1].score > res[2].score);
+
+        let res = img_bin.query_from_signature(&c, 1);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].id, 300);
+    }
+
+    #[test]
+    fn query_skips_removed() {
+        let mut img_bin: ImgBin = ImgBin::new();
+        let a = signature([0.5, 0.1, 0.1], 0);
+        let b = signature([0.4, 0.2, 0.1], 20);
+        img_bin.add_image_in_memory(1, 100, &a);
+        img_bin.add_image_in_memory(2, 200, &b);
+        assert_eq!(img_bin.remove_image(100, &a), Some(1));
+        assert_eq!(img_bin.remove_image(100, &a), None);
+
+        let res = img_bin.query_from_signature(&a, 10);
+        assert_eq!(res.iter().map(|r| r.id).collect::<Vec<_>>(), [200]);
+    }
+
+    #[test]
+    fn add_image_assigns_ids() {
+        let mut img_bin: ImgBin = ImgBin::new();
+        let a = signature([0.5, 0.1, 0.1], 0);
+        let b = signature([0.4, 0.2, 0.1], 20);
+        img_bin.add_image_in_memory(7, 100, &a);
+        assert_eq!(img_bin.add_image(200, &b).unwrap(), 8);
+        assert_eq!(img_bin.add_image(100, &b).unwrap_err().kind(), "conflict");
+
+        img_bin.remove_image(100, &a);
+        assert_eq!(img_bin.add_image(100, &b).unwrap(), 9);
+        let mut ids: Vec<PostId> = img_bin
+            .query_from_signature(&b, 10)
+            .iter()
+            .map(|r| r.id)
+            .collect();
+        ids.sort();
+        assert_eq!(ids, [100, 200]);
+    }
+
+    #[test]
+    fn query_in_parallel() {
+        let mut img_bin: ImgBin = ImgBin::new();
+        let sigs: Vec<HaarSignature> = (0..8)
+            .map(|i| signature([0.5, 0.1, 0.1 + i as f64 / 100.0], i * 50))
+            .collect();
+        for (i, sig) in sigs.iter().enumerate() {
+            img_bin.add_image_in_memory(i as IqdbId + 1, i as PostId + 100, sig);
+        }
+
+        // Queries only borrow the index, so they can share it between threads
+        let img_bin: &ImgBin = &img_bin;
+        std::thread::scope(|s| {
+            for (i, sig) in sigs.iter().enumerate() {
+                s.spawn(move || {
+                    let res = img_bin.query_from_signature(sig, 1);
+                    assert_eq!(res[0].id, i as PostId + 100);
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn query_over_snapshot() {
+        let a = signature([0.5, 0.1, 0.1], 0);
+        let b = signature([0.4, 0.2, 0.1], 20);
+        let c = signature([0.1, 0.3, 0.2], 1000);
+        let d = signature([0.3, 0.1, 0.2], 10);
+        let mut img_bin: ImgBin = ImgBin::new();
+        img_bin.add_image_in_memory(1, 100, &a);
+        img_bin.add_image_in_memory(2, 200, &b);
+        img_bin.add_image_in_memory(3, 300, &c);
+
+        let path =
+            std::env::temp_dir().join(format!("oiqdb-imgdb-{}.snapshot", std::process::id()));
+        snapshot::save(&path, &img_bin, 0).unwrap();
+        let (mut mapped, _) = snapshot::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Removes, replacements and adds on top of the mapped buckets give the same results as
+        // on an index that was built in memory
+        for img_bin in [&mut img_bin, &mut mapped] {
+            img_bin.remove_image(100, &a);
+            img_bin.remove_image(200, &b);
+            img_bin.add_image_in_memory(2, 200, &d);
+            img_bin.add_image_in_memory(4, 400, &a);
+        }
+        for sig in [&a, &b, &c, &d] {
+            let expected = img_bin.query_from_signature(sig, 10);
+            let res = mapped.query_from_signature(sig, 10);
+            assert_eq!(
+                res.iter().map(|r| (r.id, r.score)).collect::<Vec<_>>(),
+                expected.iter().map(|r| (r.id, r.score)).collect::<Vec<_>>()
+            );
+        }
+        assert_eq!(mapped.query_from_signature(&a, 1)[0].id, 400);
+        assert_eq!(mapped.query_from_signature(&d, 1)[0].id, 200);
+    }
+
+    #[test]
+    fn stats_counts() {
+        let mut img_bin: ImgBin = ImgBin::new();
+        let a = signature([0.5, 0.1, 0.1], 0);
+        let b = signature([0.4, 0.2, 0.1], 20);
+        img_bin.add_image_in_memory(1, 100, &a);
+        img_bin.add_image_in_memory(3, 300, &b);
+        img_bin.remove_image(100, &a);
+
+        let stats = img_bin.stats();
+        assert_eq!(stats.images, 1);
+        // Slots 0 and 2 were never used, and slot 1 was removed
+        assert_eq!(stats.deleted, 3);
+        assert_eq!(stats.info_used, 4);
+        assert!(stats.info_capacity >= stats.info_used);
+        assert_eq!(stats.postings, 3 * NUM_COEFS);
+        assert_eq!(stats.delta_postings, stats.postings);
+        assert_eq!(stats.largest_buckets.len(), 6);
+        assert_eq!(
+            stats.largest_buckets[0],
+            BucketStats {
+                color: 0,
+                sign: '+',
+                index: 21,
+                postings: 1
+            }
+        );
+        assert_eq!(stats.largest_buckets[1].postings, 0);
+        assert!(stats.memory_bytes > 0);
+        assert_eq!(stats.mapped_bytes, 0);
+
+        // Post 0 with a black image in the last slot doesn't count towards `info_used`
+        img_bin.add_image_in_memory(4, 0, &signature([0.0, 0.0, 0.0], 40));
+        let stats = img_bin.stats();
+        assert_eq!((stats.images, stats.info_used, stats.deleted), (2, 4, 3));
+    }
+
+    #[test]
+    fn query_empty() {
+        let img_bin: ImgBin = ImgBin::new();
+        let a = signature([0.5, 0.1, 0.1], 0);
+        assert!(img_bin.query_from_signature(&a, 10).is_empty());
     }
 }
@@ -0,0 +1,351 @@
+use crc32fast::Hasher;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::Path;
+
+use crate::iqdb::imgdb::{ImgBin, IqdbId, PostId, N_BUCKETS};
+
+const MAGIC: &[u8; 8] = b"OIQDBIDX";
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+// Magic, version, reserved, generation, info length and postings length.
+const HEADER_LEN: usize = 40;
+// Post ID and the three averages.
+const INFO_ENTRY_LEN: usize = 16;
+
+/// Writes `img_bin` to `path`, tagged with the database `generation` it reflects.
+///
+/// The layout can be mapped and queried in place, see [`Mapped`]. All numbers are little endian:
+///
+/// - the header: magic bytes, format version (u32), a reserved u32, the generation (i64), the
+///   info length (u64) and the number of postings (u64)
+/// - the info vector, each entry a post ID (u32) and averages (3 f32)
+/// - the CSR offsets: for every bucket, by color, sign and coefficient index, where its postings
+///   start (u64), followed by the number of postings
+/// - the postings, as IQDB IDs (u32)
+/// - a CRC-32 of everything before it
+///
+/// The file is written to a temporary path first and renamed over `path`, so a crash never
+/// leaves a half-written snapshot, and processes that have the old one mapped keep their pages.
+pub fn save(path: &Path, img_bin: &ImgBin, generation: i64) -> io::Result<()> {
+    let counts: Vec<u64> = (0..N_BUCKETS)
+        .map(|b| img_bin.postings(b).count() as u64)
+        .collect();
+    let info_len = img_bin.info_entries().count() as u64;
+
+    let tmp = path.with_extension("tmp");
+    let mut w = Checksummed::new(BufWriter::new(File::create(&tmp)?));
+    w.write_all(MAGIC)?;
+    w.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+    w.write_all(&0u32.to_le_bytes())?;
+    w.write_all(&generation.to_le_bytes())?;
+    w.write_all(&info_len.to_le_bytes())?;
+    w.write_all(&counts.iter().sum::<u64>().to_le_bytes())?;
+
+    for (id, avgl) in img_bin.info_entries() {
+        w.write_all(&id.to_le_bytes())?;
+        for v in avgl {
+            w.write_all(&v.to_le_bytes())?;
+        }
+    }
+    let mut offset: u64 = 0;
+    for count in counts.iter() {
+        w.write_all(&offset.to_le_bytes())?;
+        offset += count;
+    }
+    w.write_all(&offset.to_le_bytes())?;
+    for b in 0..N_BUCKETS {
+        for iqdb_id in img_bin.postings(b) {
+            w.write_all(&iqdb_id.to_le_bytes())?;
+        }
+    }
+
+    let checksum = w.hasher.finalize();
+    let mut inner = w.inner;
+    inner.write_all(&checksum.to_le_bytes())?;
+    inner.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Maps the snapshot at `path` and builds an index over it, returning the index and the
+/// generation it reflects.
+pub fn load(path: &Path) -> io::Result<(ImgBin, i64)> {
+    let mapped = Mapped::open(path)?;
+    let generation = mapped.generation();
+    Ok((ImgBin::from_mapped(mapped), generation))
+}
+
+/// A snapshot file mapped read-only into memory.
+///
+/// The bucket lists are served straight from the mapped pages, so a large index isn't copied
+/// onto the heap, and processes that map the same snapshot share its pages.
+pub struct Mapped {
+    map: Mmap,
+    generation: i64,
+    offsets_at: usize,
+    postings_at: usize,
+}
+
+impl Mapped {
+    /// Maps and validates the snapshot at `path`.
+    ///
+    /// Fails with [`ErrorKind::InvalidData`] if the file isn't a snapshot, has another format
+    /// version, doesn't match its checksum or holds out of range offsets or postings.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if cfg!(target_endian = "big") {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "snapshots can only be mapped on little endian machines",
+            ));
+        }
+        let file = File::open(path)?;
+        // Safety: snapshots are only ever replaced by renaming a new file over them, never
+        // written in place, so the mapped file doesn't change under us.
+        let map = unsafe { Mmap::map(&file)? };
+
+        if map.len() < HEADER_LEN + 4 || &map[..8] != MAGIC {
+            return Err(invalid_data("not an index snapshot".to_string()));
+        }
+        let version = u32::from_le_bytes(map[8..12].try_into().unwrap());
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported snapshot version {} (expected {})",
+                version, SNAPSHOT_VERSION
+            )));
+        }
+        let (body, trailer) = map.split_at(map.len() - 4);
+        if crc32fast::hash(body) != u32::from_le_bytes(trailer.try_into().unwrap()) {
+            return Err(invalid_data("snapshot checksum mismatch".to_string()));
+        }
+
+        let generation = i64::from_le_bytes(map[16..24].try_into().unwrap());
+        let info_len = u64::from_le_bytes(map[24..32].try_into().unwrap());
+        let postings_len = u64::from_le_bytes(map[32..40].try_into().unwrap());
+        let layout = (info_len as usize)
+            .checked_mul(INFO_ENTRY_LEN)
+            .and_then(|info| info.checked_add(HEADER_LEN))
+            .and_then(|offsets_at| {
+                let postings_at = offsets_at + 8 * (N_BUCKETS + 1);
+                let end = (postings_len as usize)
+                    .checked_mul(4)?
+                    .checked_add(postings_at)?;
+                Some((offsets_at, postings_at, end))
+            });
+        let Some((offsets_at, postings_at, end)) = layout else {
+            return Err(invalid_data(
+                "snapshot lengths are out of range".to_string(),
+            ));
+        };
+        if end != body.len() || info_len > IqdbId::MAX as u64 + 1 {
+            return Err(invalid_data(
+                "snapshot lengths don't match its size".to_string(),
+            ));
+        }
+
+        let mapped = Mapped {
+            map,
+            generation,
+            offsets_at,
+            postings_at,
+        };
+        let offsets = mapped.offsets();
+        if offsets[0] != 0
+            || offsets.windows(2).any(|w| w[0] > w[1])
+            || offsets[N_BUCKETS] != postings_len
+        {
+            return Err(invalid_data(
+                "snapshot offsets are out of order".to_string(),
+            ));
+        }
+        if mapped.postings().iter().any(|&p| p as u64 >= info_len) {
+            return Err(invalid_data(
+                "snapshot postings are out of range".to_string(),
+            ));
+        }
+        Ok(mapped)
+    }
+
+    pub fn generation(&self) -> i64 {
+        self.generation
+    }
+
+    /// Returns the size of the mapped file.
+    pub fn file_len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns the post ID and averages of every slot of the info vector, by IQDB ID.
+    pub fn info(&self) -> impl Iterator<Item = (PostId, [f32; 3])> + '_ {
+        self.map[HEADER_LEN..self.offsets_at]
+            .chunks_exact(INFO_ENTRY_LEN)
+            .map(|entry| {
+                let word = |i: usize| u32::from_le_bytes(entry[i..i + 4].try_into().unwrap());
+                (
+                    word(0),
+                    [
+                        f32::from_bits(word(4)),
+                        f32::from_bits(word(8)),
+                        f32::from_bits(word(12)),
+                    ],
+                )
+            })
+    }
+
+    /// Returns the postings of the bucket at flat index `b`.
+    pub fn bucket(&self, b: usize) -> &[IqdbId] {
+        let offsets = self.offsets();
+        &self.postings()[offsets[b] as usize..offsets[b + 1] as usize]
+    }
+
+    fn offsets(&self) -> &[u64] {
+        cast(&self.map[self.offsets_at..self.postings_at])
+    }
+
+    fn postings(&self) -> &[u32] {
+        cast(&self.map[self.postings_at..self.map.len() - 4])
+    }
+}
+
+/// Reinterprets little endian bytes as a slice of `T`.
+///
+/// The header and info entries keep every array 8 byte aligned relative to the page aligned
+/// mapping, and any bit pattern is a valid u32 or u64.
+fn cast<T: Copy>(bytes: &[u8]) -> &[T] {
+    // Safety: only used for u32 and u64, which have no invalid bit patterns
+    let (prefix, values, suffix) = unsafe { bytes.align_to::<T>() };
+    assert!(prefix.is_empty() && suffix.is_empty());
+    values
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Passes writes through to `inner`, keeping a CRC-32 of the bytes.
+struct Checksummed<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W> Checksummed<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Hasher::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::iqdb::IQDB;
+    use crate::signature::haar::NUM_COEFS;
+    use crate::signature::HaarSignature;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("oiqdb-{}-{}.snapshot", name, std::process::id()))
+    }
+
+    fn test_signature() -> HaarSignature {
+        let mut sig = HaarSignature::new();
+        sig.avglf = [0.5, 0.25, 0.125];
+        for i in 0..NUM_COEFS {
+            sig.sig0.sig[i] = i as i16 + 1;
+            sig.sig1.sig[i] = -(i as i16) - 1;
+            sig.sig2.sig[i] = i as i16 + 100;
+        }
+        sig
+    }
+
+    fn test_bin() -> ImgBin {
+        let mut img_bin = ImgBin::new();
+        img_bin.add_image_in_memory(1, 100, &test_signature());
+        img_bin
+    }
+
+    #[test]
+    fn save_load_roundtrip() {
+        let path = temp_path("roundtrip");
+        save(&path, &test_bin(), 42).unwrap();
+        let (img_bin, generation) = load(&path).unwrap();
+        assert_eq!(generation, 42);
+
+        let res = img_bin.query_from_signature(&test_signature(), 10);
+        assert_eq!(res.iter().map(|r| r.id).collect::<Vec<_>>(), [100]);
+        assert!((res[0].score - 100.0).abs() < 1e-3);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_rejects_bad_files() {
+        let path = temp_path("corrupt");
+        save(&path, &test_bin(), 7).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+
+        // A flipped bit in the info vector is caught by the checksum
+        let mut corrupt = bytes.clone();
+        corrupt[HEADER_LEN] ^= 1;
+        std::fs::write(&path, &corrupt).unwrap();
+        let err = load(&path).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("checksum"));
+
+        let mut older = bytes.clone();
+        older[8..12].copy_from_slice(&1u32.to_le_bytes());
+        std::fs::write(&path, &older).unwrap();
+        let err = load(&path).err().unwrap();
+        assert!(err.to_string().contains("version"));
+
+        std::fs::write(&path, b"not a snapshot").unwrap();
+        assert_eq!(load(&path).err().unwrap().kind(), ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn outside_writes_make_it_stale() {
+        let db = temp_path("outside").with_extension("sqlite");
+        let _ = std::fs::remove_file(&db);
+        let mut config = Config::for_tests();
+        config.database.url = format!("sqlite://{}?mode=rwc", db.display());
+        config.snapshot_path = Some(temp_path("outside"));
+
+        let server = IQDB::new(&config).await.unwrap();
+        server.add_image(1, &test_signature()).await.unwrap();
+        // Like `oiqdb add` while the server is running
+        let cli = IQDB::connect(&config).await.unwrap();
+        cli.add_image(2, &test_signature()).await.unwrap();
+        server.save_snapshot().await.unwrap();
+
+        // The snapshot is missing post 2, so the database is replayed instead
+        let iqdb = IQDB::new(&config).await.unwrap();
+        let status = iqdb.status().await.unwrap();
+        assert_eq!((status.index.images, status.index.mapped_bytes), (2, 0));
+
+        // Its own writes keep the snapshot current
+        iqdb.remove_image(1).await.unwrap();
+        iqdb.save_snapshot().await.unwrap();
+        let status = IQDB::new(&config).await.unwrap().status().await.unwrap();
+        assert_eq!(status.index.images, 1);
+        assert!(status.index.mapped_bytes > 0);
+
+        std::fs::remove_file(config.snapshot_path.unwrap()).unwrap();
+        std::fs::remove_file(&db).unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+//! Importing the databases of the C++ iqdb, so a deployment can switch over without recomputing
+//! every signature.
+//!
+//! - [`sqlite`] reads the SQLite database of danbooru's iqdb.
+//! - [`binary`] reads the binary `.db` file of the original iqdb.
+
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+use crate::iqdb::{AddStatus, PostId, IQDB};
+use crate::signature::haar;
+use crate::signature::HaarSignature;
+
+pub mod binary;
+pub mod sqlite;
+
+/// How many rows are added under one lock and database transaction.
+const CHUNK_SIZE: usize = 1000;
+
+/// Length of the C++ `Idx sig[3][NUM_COEFS]` array, as little-endian int16.
+pub const CPP_SIG_LEN: usize = haar::N_COLORS * haar::NUM_COEFS * 2;
+
+/// The outcome of an import. Only the rows that failed are listed, since a database can have
+/// millions of rows.
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    /// Number of rows read, whether they were added or not.
+    pub rows: usize,
+    pub inserted: usize,
+    pub updated: usize,
+    pub failed: usize,
+    pub failures: Vec<RowFailure>,
+}
+
+/// A row that couldn't be converted to a signature, and was skipped.
+#[derive(Debug, Serialize)]
+pub struct RowFailure {
+    /// ID of the row in the source database, or its position if the ID can't be read. The
+    /// position of the record in a binary one.
+    pub row_id: i64,
+    /// `None` if the row had no readable post ID.
+    pub post_id: Option<PostId>,
+    pub error: &'static str,
+    pub message: String,
+}
+
+impl ImportReport {
+    fn fail(&mut self, row_id: i64, post_id: Option<PostId>, e: &Error) {
+        self.failed += 1;
+        self.failures.push(RowFailure {
+            row_id,
+            post_id,
+            error: e.kind(),
+            message: e.to_string(),
+        });
+    }
+}
+
+/// Adds the images of `chunk` to `iqdb` and empties it.
+async fn add_chunk(
+    iqdb: &IQDB,
+    chunk: &mut Vec<(PostId, HaarSignature)>,
+    report: &mut ImportReport,
+) -> Result<()> {
+    if chunk.is_empty() {
+        return Ok(());
+    }
+    for status in iqdb.add_images(chunk).await? {
+        match status {
+            AddStatus::Inserted => report.inserted += 1,
+            AddStatus::Updated => report.updated += 1,
+        }
+    }
+    chunk.clear();
+    Ok(())
+}
+
+/// Converts the C++ in-memory layout of a signature: the coefficients of each channel in turn,
+/// as little-endian int16, with the average luminance kept apart.
+///
+/// Fails with [`Error::Validation`] unless the signature passes [`HaarSignature::validate`].
+pub fn signature_from_cpp(avglf: haar::Lumin, sig: &[u8]) -> Result<HaarSignature> {
+    if sig.len() != CPP_SIG_LEN {
+        return Err(Error::Validation(format!(
+            "signature is {} bytes, expected {}",
+            sig.len(),
+            CPP_SIG_LEN
+        )));
+    }
+    let mut coefs = sig
+        .chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]));
+    let mut haar = HaarSignature::new();
+    haar.avglf = avglf;
+    for s in [&mut haar.sig0, &mut haar.sig1, &mut haar.sig2] {
+        for (s, coef) in s.sig.iter_mut().zip(coefs.by_ref()) {
+            *s = coef;
+        }
+    }
+    haar.validate()?;
+    Ok(haar)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A signature as the C++ iqdb lays it out, with coefficients 1..=120.
+    pub(crate) fn cpp_sig() -> Vec<u8> {
+        (1..=(haar::N_COLORS * haar::NUM_COEFS) as i16)
+            .flat_map(|coef| coef.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn converts_cpp_signatures() {
+        let avglf = [0.5, -0.01, 0.02];
+        let haar = signature_from_cpp(avglf, &cpp_sig()).unwrap();
+        assert_eq!(haar.avglf, avglf);
+        assert_eq!(haar.sig0.sig[0], 1);
+        assert_eq!(haar.sig2.sig[haar::NUM_COEFS - 1], 120);
+
+        let err = |avglf, sig: &[u8]| signature_from_cpp(avglf, sig).unwrap_err().to_string();
+        assert!(err(avglf, &cpp_sig()[2..]).contains("238 bytes"));
+        assert!(err([f64::NAN, 0.0, 0.0], &cpp_sig()).contains("not a number"));
+        let mut sig = cpp_sig();
+        sig[..2].copy_from_slice(&i16::MIN.to_le_bytes());
+        assert!(err(avglf, &sig).contains("out of range"));
+        sig[..2].copy_from_slice(&0i16.to_le_bytes());
+        assert!(err(avglf, &sig).contains("out of range"));
+    }
+}
@@ -0,0 +1,373 @@
+//! The binary `.db` file written by `imgdb.cpp` of the original iqdb. Everything is
+//! little-endian:
+//!
+//! - The header: the version code as a `u32`, then the number of images as a `u32`. Like
+//!   `SRZ_V_CODE` in `imgdb.cpp`, the low byte of the code is the format version, and the bits
+//!   above it describe the sizes of the C++ types the file was written with.
+//! - One record per image, of [`RECORD_LEN`] bytes: the image ID as a `u64`, the signature as the
+//!   C++ `Idx sig[3][NUM_COEFS]` array (see [`signature_from_cpp`]), the 3 `avgl` doubles, then
+//!   the width and height as `i32`.
+//! - The bucket contents, for each color, sign (positive first) and coefficient index in turn: the
+//!   number of images in the bucket as a `u32`, then the position of each of their records as a
+//!   `u32`.
+//!
+//! The buckets can be rebuilt from the records, so they're only read to check that the file is
+//! whole.
+//!
+//! This layout comes from reading `imgdb.cpp`, and hasn't been checked against a file written by
+//! it. In particular, how the type sizes are packed into the version code isn't known, so they're
+//! kept in [`BinaryDb::sizes`] but not checked. A file written with other type sizes than the ones
+//! above has records and buckets of other lengths, and fails as truncated or with buckets that
+//! don't match the records, instead of being read wrongly.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use tokio::task;
+
+use crate::error::{Error, Result};
+use crate::iqdb::{ImgBin, PostId, IQDB};
+use crate::legacy::{add_chunk, signature_from_cpp, ImportReport, CHUNK_SIZE, CPP_SIG_LEN};
+use crate::signature::haar::{self, NUM_COEFS, NUM_PIXELS_SQUARED};
+use crate::signature::HaarSignature;
+
+/// The only version that can be read, `SRZ_V0_9_0` in `imgdb.cpp`, in the low byte of the version
+/// code.
+pub const VERSION: u32 = 9;
+/// Length of an image record: the ID, the signature, avgl, width and height.
+pub const RECORD_LEN: usize = 8 + CPP_SIG_LEN + haar::N_COLORS * 8 + 2 * 4;
+
+/// The images of a binary database, without the rows that couldn't be converted.
+#[derive(Debug)]
+pub struct BinaryDb {
+    pub version: u32,
+    /// The type sizes in the version code, the bits above [`BinaryDb::version`].
+    pub sizes: u32,
+    /// Post IDs are unique, the later records of a post are reported as failed.
+    pub images: Vec<(PostId, HaarSignature)>,
+    /// Counts the records read and lists the ones that failed. Nothing is added yet.
+    pub report: ImportReport,
+}
+
+impl BinaryDb {
+    /// Builds an in-memory index of the images, without storing them.
+    pub fn into_img_bin(self) -> ImgBin {
+        let mut img_bin = ImgBin::new();
+        for (post_id, haar) in self.images.iter() {
+            img_bin
+                .add_image(*post_id, haar)
+                .expect("post IDs are unique");
+        }
+        img_bin
+    }
+
+    /// Adds the images to `iqdb`, which stores them in its database, and returns the report
+    /// with the number inserted and updated.
+    pub async fn persist(self, iqdb: &IQDB) -> Result<ImportReport> {
+        let mut report = self.report;
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        for image in self.images {
+            chunk.push(image);
+            if chunk.len() >= CHUNK_SIZE {
+                add_chunk(iqdb, &mut chunk, &mut report).await?;
+            }
+        }
+        add_chunk(iqdb, &mut chunk, &mut report).await?;
+        Ok(report)
+    }
+}
+
+/// Reads the binary database at `path` and adds its images to `iqdb`, see [`read`] and
+/// [`BinaryDb::persist`].
+pub async fn import(iqdb: &IQDB, path: &Path) -> Result<ImportReport> {
+    let path = path.to_owned();
+    let db = task::spawn_blocking(move || read(&path)).await??;
+    db.persist(iqdb).await
+}
+
+/// Reads the binary database at `path`, see [`read_from`].
+pub fn read(path: &Path) -> Result<BinaryDb> {
+    let file = File::open(path)?;
+    read_from(BufReader::new(file)).map_err(|e| match e {
+        Error::Validation(msg) => Error::Validation(format!("{}: {}", path.display(), msg)),
+        e => e,
+    })
+}
+
+/// Reads a binary database, converting every record.
+///
+/// Records that can't be converted are reported and skipped. Fails with [`Error::Validation`] if
+/// the version in the low byte of the version code isn't [`VERSION`], or if the file is truncated or its buckets don't match the
+/// records.
+pub fn read_from(mut reader: impl Read) -> Result<BinaryDb> {
+    let code = read_u32(&mut reader)?;
+    let (version, sizes) = (code & 0xff, code >> 8);
+    if version != VERSION {
+        return Err(Error::Validation(format!(
+            "unsupported iqdb database version {}, only version {} can be read",
+            version, VERSION
+        )));
+    }
+    let count = read_u32(&mut reader)? as usize;
+
+    let mut report = ImportReport {
+        rows: count,
+        ..Default::default()
+    };
+    let mut post_ids = HashSet::new();
+    // The failed records are kept as `None`, so bucket entries still point at the right one
+    let mut records: Vec<Option<(PostId, HaarSignature)>> = Vec::new();
+    let mut record = [0u8; RECORD_LEN];
+    for index in 0..count {
+        read_exact(&mut reader, &mut record)?;
+        let converted = convert(&record).and_then(|(post_id, haar)| {
+            if post_ids.insert(post_id) {
+                Ok((post_id, haar))
+            } else {
+                let e = Error::Conflict(format!("post {} has more than one record", post_id));
+                Err((Some(post_id), e))
+            }
+        });
+        records.push(match converted {
+            Ok(image) => Some(image),
+            Err((post_id, e)) => {
+                report.fail(index as i64, post_id, &e);
+                None
+            }
+        });
+    }
+
+    check_buckets(&mut reader, &records)?;
+    if reader.read(&mut [0])? != 0 {
+        return Err(Error::Validation(
+            "there's data after the buckets".to_string(),
+        ));
+    }
+    Ok(BinaryDb {
+        version,
+        sizes,
+        images: records.into_iter().flatten().collect(),
+        report,
+    })
+}
+
+/// Reads the post ID and signature of a record, or why it can't be added.
+fn convert(record: &[u8; RECORD_LEN]) -> Result<(PostId, HaarSignature), (Option<PostId>, Error)> {
+    let (id, rest) = record.split_at(8);
+    let (sig, rest) = rest.split_at(CPP_SIG_LEN);
+    let id = u64::from_le_bytes(id.try_into().unwrap());
+    let post_id = PostId::try_from(id).map_err(|_| {
+        let e = Error::Validation(format!("image ID {} is out of range", id));
+        (None, e)
+    })?;
+    let mut avglf: haar::Lumin = Default::default();
+    for (avgl, bytes) in avglf.iter_mut().zip(rest.chunks_exact(8)) {
+        *avgl = f64::from_le_bytes(bytes.try_into().unwrap());
+    }
+    signature_from_cpp(avglf, sig)
+        .map(|haar| (post_id, haar))
+        .map_err(|e| (Some(post_id), e))
+}
+
+/// Checks that every bucket entry is a coefficient of the record it points at, and that every
+/// coefficient of the converted records has exactly one entry.
+fn check_buckets(
+    reader: &mut impl Read,
+    records: &[Option<(PostId, HaarSignature)>],
+) -> Result<()> {
+    let mut postings = 0;
+    let mut seen = HashSet::new();
+    for color in 0..haar::N_COLORS {
+        for sign in [1, -1] {
+            for index in 0..NUM_PIXELS_SQUARED {
+                let coef = sign * index as i16;
+                seen.clear();
+                for _ in 0..read_u32(reader)? {
+                    let position = read_u32(reader)? as usize;
+                    // Would make up for a missing entry in the count below
+                    if !seen.insert(position) {
+                        return Err(Error::Validation(format!(
+                            "the bucket of coefficient {} in color {} lists record {} twice",
+                            coef, color, position
+                        )));
+                    }
+                    let record = records.get(position).ok_or_else(|| {
+                        Error::Validation(format!(
+                            "a bucket lists record {}, but there are only {}",
+                            position,
+                            records.len()
+                        ))
+                    })?;
+                    let Some((_, haar)) = record else {
+                        continue;
+                    };
+                    if color >= haar.num_colors() || !haar[color].contains(&coef) {
+                        return Err(Error::Validation(format!(
+                            "the bucket of coefficient {} in color {} lists record {}, which \
+                             doesn't have it",
+                            coef, color, position
+                        )));
+                    }
+                    postings += 1;
+                }
+            }
+        }
+    }
+
+    let expected: usize = records
+        .iter()
+        .flatten()
+        .map(|(_, haar)| haar.num_colors() * NUM_COEFS)
+        .sum();
+    if postings != expected {
+        return Err(Error::Validation(format!(
+            "the buckets have {} entries, but the records have {} coefficients",
+            postings, expected
+        )));
+    }
+    Ok(())
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    read_exact(reader, &mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::Validation("the database is truncated".to_string()),
+        _ => Error::from(e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::legacy::tests::cpp_sig;
+
+    /// Type sizes packed into the version code by [`write`], any value is read the same.
+    const SIZES: u32 = 0x48_8424;
+
+    /// Writes a database the way `imgdb.cpp` does, with the records in order.
+    fn write(records: &[(u64, Vec<u8>, haar::Lumin)]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(SIZES << 8 | VERSION).to_le_bytes());
+        bytes.extend_from_slice(&(records.len() as u32).to_le_bytes());
+        for (id, sig, avglf) in records {
+            bytes.extend_from_slice(&id.to_le_bytes());
+            bytes.extend_from_slice(sig);
+            for avgl in avglf {
+                bytes.extend_from_slice(&avgl.to_le_bytes());
+            }
+            bytes.extend_from_slice(&[128, 0, 0, 0, 96, 0, 0, 0]);
+        }
+        let sigs: Vec<HaarSignature> = records
+            .iter()
+            .map(|(_, sig, avglf)| signature_from_cpp(*avglf, sig).unwrap())
+            .collect();
+        for color in 0..haar::N_COLORS {
+            for sign in [1, -1] {
+                for index in 0..NUM_PIXELS_SQUARED {
+                    let coef = sign * index as i16;
+                    let bucket: Vec<u32> = (0..sigs.len() as u32)
+                        .filter(|&i| {
+                            let sig = &sigs[i as usize];
+                            color < sig.num_colors() && sig[color].contains(&coef)
+                        })
+                        .collect();
+                    bytes.extend_from_slice(&(bucket.len() as u32).to_le_bytes());
+                    for i in bucket {
+                        bytes.extend_from_slice(&i.to_le_bytes());
+                    }
+                }
+            }
+        }
+        bytes
+    }
+
+    #[tokio::test]
+    async fn reads_binary_database() {
+        let color = [0.5, 0.1, -0.1];
+        let gray = [0.5, 0.0, 0.0];
+        let mut negative = cpp_sig();
+        negative[..2].copy_from_slice(&(-7i16).to_le_bytes());
+        let bytes = write(&[
+            (10, cpp_sig(), color),
+            (11, negative, gray),
+            (1 << 40, cpp_sig(), color),
+            (10, cpp_sig(), gray),
+        ]);
+        // 2 color and 2 grayscale records, each coefficient in one bucket
+        let postings = 2 * 3 * NUM_COEFS + 2 * NUM_COEFS;
+        let buckets = 8 + 4 * RECORD_LEN;
+        assert_eq!(bytes.len(), buckets + 4 * (98304 + postings));
+
+        let db = read_from(&bytes[..]).unwrap();
+        assert_eq!((db.version, db.sizes), (VERSION, SIZES));
+        assert_eq!(db.images.len(), 2);
+        assert_eq!(db.images[1].1.sig0.sig[0], -7);
+        let failed: Vec<_> = db
+            .report
+            .failures
+            .iter()
+            .map(|f| (f.row_id, f.post_id, f.error))
+            .collect();
+        assert_eq!(failed, [(2, None, "validation"), (3, Some(10), "conflict")]);
+        let query = db.images[0].1.clone();
+        let img_bin = db.into_img_bin();
+        assert_eq!(img_bin.query_from_signature(&query, 1)[0].id, 10);
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[0] = 5;
+        let err = read_from(&wrong_version[..]).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "unsupported iqdb database version 5, only version 9 can be read"
+        );
+        // A bare version word has no type sizes
+        let mut bare = bytes.clone();
+        bare[..4].copy_from_slice(&VERSION.to_le_bytes());
+        let db = read_from(&bare[..]).unwrap();
+        assert_eq!((db.version, db.sizes, db.images.len()), (VERSION, 0, 2));
+        let err = read_from(&bytes[..bytes.len() - 1])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "the database is truncated");
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        let err = read_from(&trailing[..]).unwrap_err().to_string();
+        assert_eq!(err, "there's data after the buckets");
+        // The bucket of coefficient 1 lists records 0, 2 and 3, point its first entry elsewhere
+        let mut moved = bytes.clone();
+        assert_eq!(moved[buckets + 4..buckets + 8], 3u32.to_le_bytes());
+        moved[buckets + 8] = 1;
+        let err = read_from(&moved[..]).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "the bucket of coefficient 1 in color 0 lists record 1, which doesn't have it"
+        );
+        let mut twice = bytes.clone();
+        twice[buckets + 12] = 0;
+        let err = read_from(&twice[..]).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "the bucket of coefficient 1 in color 0 lists record 0 twice"
+        );
+
+        let dir = std::env::temp_dir().join(format!("oiqdb-binary-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("iqdb.db");
+        std::fs::write(&path, &bytes).unwrap();
+        let mut config = Config::for_tests();
+        config.database.url = format!("sqlite://{}?mode=rwc", dir.join("oiqdb.sqlite").display());
+        let iqdb = IQDB::connect(&config).await.unwrap();
+        let report = import(&iqdb, &path).await.unwrap();
+        assert_eq!((report.rows, report.inserted, report.failed), (4, 2, 2));
+        let sig = iqdb.get_signature(11).await.unwrap().unwrap();
+        assert!(sig.is_grayscale());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
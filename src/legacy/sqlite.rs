@@ -0,0 +1,232 @@
+//! The SQLite database of danbooru's iqdb, which keeps every post in one table:
+//!
+//! ```sql
+//! CREATE TABLE images (
+//!     id INTEGER PRIMARY KEY NOT NULL,
+//!     post_id INTEGER UNIQUE NOT NULL,
+//!     avglf1 REAL NOT NULL,
+//!     avglf2 REAL NOT NULL,
+//!     avglf3 REAL NOT NULL,
+//!     sig BLOB NOT NULL
+//! );
+//! ```
+//!
+//! `sig` is the C++ signature array as is, see [`signature_from_cpp`].
+
+use futures::TryStreamExt;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteRow};
+use sqlx::{Connection, Decode, Row, Sqlite, SqliteConnection, Type};
+use std::path::Path;
+use tracing::info;
+
+use crate::error::{Error, Result};
+use crate::iqdb::{PostId, IQDB};
+use crate::legacy::{add_chunk, signature_from_cpp, ImportReport, CHUNK_SIZE};
+use crate::signature::HaarSignature;
+
+const COLUMNS: [&str; 6] = ["id", "post_id", "avglf1", "avglf2", "avglf3", "sig"];
+
+/// Adds every row of the C++ iqdb database at `path` to `iqdb`, by row ID. The file is opened
+/// read-only.
+///
+/// Rows that can't be converted are reported and skipped. Fails with [`Error::Validation`] if
+/// the file doesn't have the C++ schema, and stops at the first storage error, keeping the chunks
+/// added before it.
+pub async fn import(iqdb: &IQDB, path: &Path) -> Result<ImportReport> {
+    let options = SqliteConnectOptions::new().filename(path).read_only(true);
+    let mut conn = SqliteConnection::connect_with(&options).await?;
+    check_schema(&mut conn, path).await?;
+
+    let mut report = ImportReport::default();
+    let mut chunk: Vec<(PostId, HaarSignature)> = Vec::with_capacity(CHUNK_SIZE);
+    let mut rows = sqlx::query(
+        r#"
+        SELECT id, post_id, avglf1, avglf2, avglf3, sig
+        FROM images
+        ORDER BY id ASC
+        "#,
+    )
+    .fetch(&mut conn);
+    while let Some(row) = rows.try_next().await? {
+        report.rows += 1;
+        let row_id: i64 = match column(&row, "id") {
+            Ok(row_id) => row_id,
+            // Reported by position instead
+            Err(e) => {
+                report.fail(report.rows as i64, None, &e);
+                continue;
+            }
+        };
+        match convert(&row) {
+            Ok(image) => chunk.push(image),
+            Err((post_id, e)) => report.fail(row_id, post_id, &e),
+        }
+        if chunk.len() >= CHUNK_SIZE {
+            add_chunk(iqdb, &mut chunk, &mut report).await?;
+        }
+        if report.rows % 100000 == 0 {
+            info!("imported {} rows", report.rows);
+        }
+    }
+    add_chunk(iqdb, &mut chunk, &mut report).await?;
+    Ok(report)
+}
+
+/// Checks that the `images` table has the columns of the C++ schema, so a wrong file fails
+/// with a clear error rather than on every row.
+async fn check_schema(conn: &mut SqliteConnection, path: &Path) -> Result<()> {
+    let columns: Vec<String> = sqlx::query_scalar("SELECT name FROM pragma_table_info('images')")
+        .fetch_all(&mut *conn)
+        .await?;
+    if columns.is_empty() {
+        return Err(Error::Validation(format!(
+            "{} is not a C++ iqdb database: it has no images table",
+            path.display()
+        )));
+    }
+    if let Some(missing) = COLUMNS
+        .iter()
+        .find(|c| !columns.iter().any(|name| name == *c))
+    {
+        return Err(Error::Validation(format!(
+            "{} is not a C++ iqdb database: its images table has no {} column",
+            path.display(),
+            missing
+        )));
+    }
+    Ok(())
+}
+
+/// Reads the post ID and signature of a row, or why it can't be added.
+fn convert(row: &SqliteRow) -> Result<(PostId, HaarSignature), (Option<PostId>, Error)> {
+    // SQLite decodes NULL as 0, so it's checked for explicitly
+    let post_id: Option<i64> = column(row, "post_id").map_err(|e| (None, e))?;
+    let post_id =
+        post_id.ok_or_else(|| (None, Error::Validation("post ID is missing".to_string())))?;
+    let post_id = PostId::try_from(post_id).map_err(|_| {
+        let e = Error::Validation(format!("post ID {} is out of range", post_id));
+        (None, e)
+    })?;
+    let signature = || -> Result<HaarSignature> {
+        let avglf = [
+            column(row, "avglf1")?,
+            column(row, "avglf2")?,
+            column(row, "avglf3")?,
+        ];
+        let sig: Vec<u8> = column(row, "sig")?;
+        signature_from_cpp(avglf, &sig)
+    };
+    signature()
+        .map(|sig| (post_id, sig))
+        .map_err(|e| (Some(post_id), e))
+}
+
+fn column<'r, T>(row: &'r SqliteRow, name: &str) -> Result<T>
+where
+    T: Decode<'r, Sqlite> + Type<Sqlite>,
+{
+    row.try_get(name)
+        .map_err(|e| Error::Validation(format!("bad {}: {}", name, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::legacy::tests::cpp_sig;
+    use sqlx::Executor;
+
+    #[tokio::test]
+    async fn imports_cpp_database() {
+        let dir = std::env::temp_dir().join(format!("oiqdb-cpp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("iqdb.sqlite");
+        let _ = std::fs::remove_file(&source);
+        let options = SqliteConnectOptions::new()
+            .filename(&source)
+            .create_if_missing(true);
+        let mut conn = SqliteConnection::connect_with(&options).await.unwrap();
+        conn.execute(
+            "CREATE TABLE images (id INTEGER PRIMARY KEY NOT NULL, post_id INTEGER UNIQUE, \
+             avglf1 REAL NOT NULL, avglf2 REAL NOT NULL, avglf3 REAL NOT NULL, sig BLOB)",
+        )
+        .await
+        .unwrap();
+        let rows: [(i64, Option<i64>, Option<Vec<u8>>); 5] = [
+            (1, Some(10), Some(cpp_sig())),
+            (2, Some(11), Some(cpp_sig()[..200].to_vec())),
+            (3, None, Some(cpp_sig())),
+            (5, Some(-4), Some(cpp_sig())),
+            (8, Some(12), Some(cpp_sig())),
+        ];
+        for (id, post_id, sig) in rows {
+            sqlx::query("INSERT INTO images VALUES (?, ?, 0.5, 0.1, -0.1, ?)")
+                .bind(id)
+                .bind(post_id)
+                .bind(sig)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+        }
+        conn.close().await.unwrap();
+
+        let target = dir.join("oiqdb.sqlite");
+        let _ = std::fs::remove_file(&target);
+        let mut config = Config::for_tests();
+        config.database.url = format!("sqlite://{}?mode=rwc", target.display());
+        let iqdb = IQDB::connect(&config).await.unwrap();
+
+        let report = import(&iqdb, &source).await.unwrap();
+        assert_eq!((report.rows, report.inserted, report.failed), (5, 2, 3));
+        let failed: Vec<_> = report
+            .failures
+            .iter()
+            .map(|f| (f.row_id, f.post_id, f.error))
+            .collect();
+        assert_eq!(
+            failed,
+            [
+                (2, Some(11), "validation"),
+                (3, None, "validation"),
+                (5, None, "validation")
+            ]
+        );
+        let sig = iqdb.get_signature(12).await.unwrap().unwrap();
+        assert_eq!(sig.avglf, [0.5, 0.1, -0.1]);
+        assert_eq!(iqdb.query(&sig, 5).await.unwrap().len(), 2);
+
+        // Importing again replaces the posts
+        let report = import(&iqdb, &source).await.unwrap();
+        assert_eq!((report.inserted, report.updated), (0, 2));
+
+        // Without a type, a row ID can be anything
+        let untyped = dir.join("untyped.sqlite");
+        let options = SqliteConnectOptions::new()
+            .filename(&untyped)
+            .create_if_missing(true);
+        let mut conn = SqliteConnection::connect_with(&options).await.unwrap();
+        conn.execute("CREATE TABLE images (id, post_id, avglf1, avglf2, avglf3, sig)")
+            .await
+            .unwrap();
+        for id in ["'x'", "2"] {
+            sqlx::query(&format!(
+                "INSERT INTO images VALUES ({}, 13, 0.5, 0.1, -0.1, ?)",
+                id
+            ))
+            .bind(cpp_sig())
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        }
+        conn.close().await.unwrap();
+        let report = import(&iqdb, &untyped).await.unwrap();
+        assert_eq!((report.rows, report.inserted, report.failed), (2, 1, 1));
+        // Text sorts after numbers, so it's the second row
+        assert_eq!(report.failures[0].row_id, 2);
+
+        let err = import(&iqdb, &target).await.unwrap_err();
+        assert!(err.to_string().contains("no avglf3 column"), "{}", err);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,45 @@
+//! An opinionated image querying database: finds images that look alike using the Haar wavelet
+//! signatures of the C++ iqdb.
+//!
+//! The pieces can be used on their own, without the HTTP server:
+//!
+//! - [`signature`] computes a [`HaarSignature`] from an image, and converts it to and from its
+//!   `iqdb_...` hash.
+//! - [`ImgBin`] is the in-memory index, which images are added to, removed from and queried.
+//! - [`Sql`] stores signatures in SQLite, and [`IQDB`] keeps an index in step with it.
+//! - [`batch`] adds many posts at once, and [`export`] writes them all out as NDJSON.
+//! - [`legacy`] imports the databases of the C++ iqdb.
+//!
+//! ```
+//! use oiqdb::{HaarSignature, ImgBin};
+//!
+//! // A 64x64 gradient, as raw RGB bytes
+//! let pixels: Vec<u8> = (0..64 * 64)
+//!     .flat_map(|i| [(i % 64) as u8 * 4, (i / 64) as u8 * 4, 128])
+//!     .collect();
+//! let sig = HaarSignature::from_rgb(64, 64, pixels).unwrap();
+//!
+//! let mut index = ImgBin::new();
+//! index.add_image(1234, &sig).unwrap();
+//! let matches = index.query_from_signature(&sig, 10);
+//! assert_eq!(matches[0].id, 1234);
+//! ```
+//!
+//! The HTTP API in [`server`] is behind the `server` feature, which is on by default.
+
+pub mod batch;
+pub mod config;
+pub mod decode;
+pub mod error;
+pub mod export;
+pub mod iqdb;
+pub mod legacy;
+pub mod metrics;
+mod resize;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod signature;
+
+pub use error::{Error, Result};
+pub use iqdb::{ImgBin, Sql, IQDB};
+pub use signature::HaarSignature;
@@ -1,13 +1,29 @@
-mod iqdb;
-mod server;
-mod signature;
+use clap::Parser;
+
+use oiqdb::config;
+
+mod cli;
 
 #[tokio::main]
 async fn main() {
-    // run our application as a hyper server on http://localhost:3000.
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, server::router().await)
-        .with_graceful_shutdown(server::shutdown_signal())
-        .await
-        .unwrap();
+    // Settings can also come from a .env file
+    dotenvy::dotenv().ok();
+    let cli = cli::Cli::parse();
+    let config = match config::Config::load(&cli.flags) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid configuration: {}", e);
+            std::process::exit(2);
+        }
+    };
+    // Logs go to stderr, so they don't mix with the output of commands
+    tracing_subscriber::fmt()
+        .with_max_level(config.log_level())
+        .with_writer(std::io::stderr)
+        .init();
+
+    if let Err(e) = cli::run(cli.command, config).await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,137 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounterVec, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+/// The process wide metrics, exposed at "/metrics" in the Prometheus text format.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// A step of adding or querying an image, timed separately to find bottlenecks.
+#[derive(Clone, Copy)]
+pub enum Stage {
+    /// Reading the image out of the multipart form.
+    Extract,
+    Decode,
+    Resize,
+    /// `haar::transform_char`, the conversion to YIQ and the wavelet transform.
+    Transform,
+    /// `haar::calc_haar`, picking the 40 largest coefficients of each channel.
+    CalcHaar,
+    /// Summing the weights of every bucket a query signature hits.
+    Scoring,
+    /// Picking the best scores out of the summed ones.
+    TopK,
+}
+
+impl Stage {
+    fn label(self) -> &'static str {
+        match self {
+            Stage::Extract => "extract",
+            Stage::Decode => "decode",
+            Stage::Resize => "resize",
+            Stage::Transform => "transform_char",
+            Stage::CalcHaar => "calc_haar",
+            Stage::Scoring => "scoring",
+            Stage::TopK => "top_k",
+        }
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    operations: IntCounterVec,
+    stages: HistogramVec,
+    lock_wait: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let operations = IntCounterVec::new(
+            Opts::new(
+                "oiqdb_operations_total",
+                "Queries, inserts and deletes, by outcome.",
+            ),
+            &["operation", "outcome"],
+        )
+        .unwrap();
+        let stages = HistogramVec::new(
+            HistogramOpts::new(
+                "oiqdb_stage_duration_seconds",
+                "Time spent in each stage of adding or querying an image.",
+            )
+            .buckets(prometheus::exponential_buckets(0.000_01, 4.0, 12).unwrap()),
+            &["stage"],
+        )
+        .unwrap();
+        let lock_wait = HistogramVec::new(
+            HistogramOpts::new(
+                "oiqdb_index_lock_wait_seconds",
+                "Time spent waiting for the index lock, by lock mode.",
+            )
+            .buckets(prometheus::exponential_buckets(0.000_001, 4.0, 14).unwrap()),
+            &["mode"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(operations.clone())).unwrap();
+        registry.register(Box::new(stages.clone())).unwrap();
+        registry.register(Box::new(lock_wait.clone())).unwrap();
+        Metrics {
+            registry,
+            operations,
+            stages,
+            lock_wait,
+        }
+    }
+
+    /// Counts one `operation`, such as "query", as succeeded or failed.
+    pub fn count(&self, operation: &str, ok: bool) {
+        let outcome = if ok { "ok" } else { "error" };
+        self.operations
+            .with_label_values(&[operation, outcome])
+            .inc();
+    }
+
+    /// Starts timing `stage`. The time is recorded when the timer is dropped.
+    pub fn time(&self, stage: Stage) -> HistogramTimer {
+        self.stages
+            .with_label_values(&[stage.label()])
+            .start_timer()
+    }
+
+    /// Records waiting `wait` for the index lock in `mode`, "read" or "write".
+    pub fn lock_waited(&self, mode: &str, wait: Duration) {
+        self.lock_wait
+            .with_label_values(&[mode])
+            .observe(wait.as_secs_f64());
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_text_format() {
+        METRICS.count("query", true);
+        drop(METRICS.time(Stage::Decode));
+        METRICS.lock_waited("read", Duration::from_micros(5));
+
+        let text = METRICS.render();
+        assert!(text.contains(r#"oiqdb_operations_total{operation="query",outcome="ok"}"#));
+        assert!(text.contains(r#"oiqdb_stage_duration_seconds_count{stage="decode"}"#));
+        assert!(text.contains(r#"oiqdb_index_lock_wait_seconds_count{mode="read"}"#));
+    }
+}
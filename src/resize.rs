@@ -1,5 +1,9 @@
+use image::{GenericImageView, ImageBuffer, Pixel, Rgba};
 use num_traits::NumCast;
 
+/// The most transparent alpha in gd, where 0 is opaque.
+const GD_ALPHA_MAX: f64 = 127.0;
+
 /**
  * Resample an image.
  *
@@ -28,22 +32,21 @@ where
     let src_w = src.width();
     let src_h = src.height();
 
-    println!("{:?} {:?}", src_w, src_h);
     for y in 0..dst_h {
         for x in 0..dst_w {
-            let (mut sx1, mut sx2): (f32, f32);
-            let (mut sx, mut sy): (f32, f32);
-            let mut s_pixels: f32 = 0.0;
-            let (mut red, mut green, mut blue, mut alpha): (f32, f32, f32, f32) =
+            let (mut sx1, mut sx2): (f64, f64);
+            let (mut sx, mut sy): (f64, f64);
+            let mut s_pixels: f64 = 0.0;
+            let (mut red, mut green, mut blue, mut alpha): (f64, f64, f64, f64) =
                 (0.0, 0.0, 0.0, 0.0);
-            let mut alpha_factor: f32;
-            let (mut alpha_sum, mut contrib_sum): (f32, f32) = (0.0, 0.0);
-            let sy1 = (y as f32) * (src_h as f32) / (dst_h as f32);
-            let sy2 = ((y + 1) as f32) * (src_h as f32) / (dst_h as f32);
+            let mut alpha_factor: f64;
+            let (mut alpha_sum, mut contrib_sum): (f64, f64) = (0.0, 0.0);
+            let sy1 = (y as f64) * (src_h as f64) / (dst_h as f64);
+            let sy2 = ((y + 1) as f64) * (src_h as f64) / (dst_h as f64);
             sy = sy1;
 
             while sy < sy2 {
-                let mut y_portion: f32;
+                let mut y_portion: f64;
                 if sy.floor() == sy1.floor() {
                     y_portion = 1.0 - (sy - sy.floor());
                     if y_portion > (sy2 - sy1) {
@@ -55,12 +58,11 @@ where
                 } else {
                     y_portion = 1.0;
                 }
-                sx1 = (x as f32) * (src_w as f32) / (dst_w as f32);
-                sx2 = ((x + 1) as f32) * (src_w as f32) / (dst_w as f32);
+                sx1 = (x as f64) * (src_w as f64) / (dst_w as f64);
+                sx2 = ((x + 1) as f64) * (src_w as f64) / (dst_w as f64);
                 sx = sx1;
                 while sx < sx2 {
-                    let mut x_portion: f32;
-                    let p_contribution: f32;
+                    let mut x_portion: f64;
                     if sx.floor() == sx1.floor() {
                         x_portion = 1.0 - (sx - sx.floor());
                         if x_portion > (sx2 - sx1) {
@@ -72,22 +74,23 @@ where
                     } else {
                         x_portion = 1.0;
                     }
-                    p_contribution = x_portion * y_portion;
-                    // Should be RGBA?
+                    let p_contribution: f64 = x_portion * y_portion;
                     let p = src.get_pixel(sx as u32, sy as u32);
                     #[allow(deprecated)]
                     let (p1, p2, p3, p4) = p.channels4();
                     // Possible rounding error here from primitive
-                    let p1: f32 = NumCast::from(p1).unwrap();
-                    let p2: f32 = NumCast::from(p2).unwrap();
-                    let p3: f32 = NumCast::from(p3).unwrap();
-                    let p4: f32 = NumCast::from(p4).unwrap();
+                    let p1: f64 = NumCast::from(p1).unwrap();
+                    let p2: f64 = NumCast::from(p2).unwrap();
+                    let p3: f64 = NumCast::from(p3).unwrap();
+                    // gd's alpha goes from 0 (opaque) to GD_ALPHA_MAX, converted like its png loader
+                    let p4: f64 = GD_ALPHA_MAX - NumCast::from(p4 >> 1).unwrap_or(0.0);
 
-                    alpha_factor = (127.0) * p_contribution; // should be 0 for these tests
+                    // Transparent pixels weigh less, fully opaque ones weigh GD_ALPHA_MAX
+                    alpha_factor = (GD_ALPHA_MAX - p4) * p_contribution;
                     red += p1 * alpha_factor;
                     green += p2 * alpha_factor;
                     blue += p3 * alpha_factor;
-                    alpha += p4 * alpha_factor;
+                    alpha += p4 * p_contribution;
                     alpha_sum += alpha_factor;
                     contrib_sum += p_contribution;
                     s_pixels += x_portion * y_portion;
@@ -114,15 +117,15 @@ where
             red = if red >= 255.5 { 255.0 } else { red + 0.5 };
             blue = if blue >= 255.5 { 255.0 } else { blue + 0.5 };
             green = if green >= 255.5 { 255.0 } else { green + 0.5 };
-            let alpha = if alpha >= 127.0 + 0.5 {
-                127.0
+            let alpha = if alpha >= GD_ALPHA_MAX + 0.5 {
+                GD_ALPHA_MAX
             } else {
                 alpha + 0.5
-            };
-            let t = Rgba([red as u8, green as u8, blue as u8, alpha as u8]);
-            //println!("First pix: {:?}", t);
+            } as u8;
+            // Back to 0 (transparent) to 255, like gd's png writer
+            let alpha = 255 - ((alpha << 1) + (alpha >> 6));
+            let t = Rgba([red as u8, green as u8, blue as u8, alpha]);
             dst.put_pixel(x, y, t);
-            //println!("dst pixel: {:?}", dst.get_pixel(x, y));
         }
     }
     dst
@@ -131,48 +134,52 @@ where
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::fs::File;
-    use std::io::{self, BufRead};
-    use std::path::Path;
+    use itertools::izip;
+    use std::fs;
     const PATH: &str = "reference/";
     const RESIZE: [&str; 3] = ["r_resize.txt", "g_resize.txt", "b_resize.txt"];
-    const ORIGINAL: [&str; 3] = ["r_buf.txt", "g_buf.txt", "b_buf.txt"];
+    const ORIGINAL: [&str; 3] = ["r_original.txt", "g_original.txt", "b_original.txt"];
 
     #[test]
     fn image_resample_test() {
-        let img = image::open("reference/peppers.jpg").unwrap();
+        // The reference image is 200x200, as decoded by gd in the C++ iqdb
         let mut imgbuf = image::ImageBuffer::new(200, 200);
-        let r_vec: Vec<u8> = read_i32_vector_file((PATH.to_owned() + ORIGINAL[0]).to_string());
-        let g_vec: Vec<u8> = read_i32_vector_file((PATH.to_owned() + ORIGINAL[1]).to_string());
-        let b_vec: Vec<u8> = read_i32_vector_file((PATH.to_owned() + ORIGINAL[2]).to_string());
+        let r_vec: Vec<u8> = read_u8_vector_file(PATH.to_owned() + ORIGINAL[0]);
+        let g_vec: Vec<u8> = read_u8_vector_file(PATH.to_owned() + ORIGINAL[1]);
+        let b_vec: Vec<u8> = read_u8_vector_file(PATH.to_owned() + ORIGINAL[2]);
 
-        let img_dimensions = (img.height() * img.width()) as usize;
-        assert_eq!(img_dimensions, r_vec.len());
+        let img_dimensions = (imgbuf.height() * imgbuf.width()) as usize;
         assert_eq!(img_dimensions, r_vec.len());
+        assert_eq!(img_dimensions, g_vec.len());
         assert_eq!(img_dimensions, b_vec.len());
 
-        // Check if the image matches the source
-        for (p, pix) in izip!(img.pixels(), imgbuf.enumerate_pixels_mut()) {
+        for pix in imgbuf.enumerate_pixels_mut() {
             let index: usize = pix.0 as usize + (200 * (pix.1 as usize));
-            *pix.2 = image::Rgba([r_vec[index], g_vec[index], b_vec[index], 0]);
-            //println!("{:?}", r_vec[index]);
-            //assert_eq!(p.2[0], r_vec[index]);
+            *pix.2 = image::Rgba([r_vec[index], g_vec[index], b_vec[index], 255]);
         }
 
-        imgbuf = image_resample(&imgbuf, 128, 128);
-        //println!("imgbuf {:?}", imgbuf.get_pixel(0, 0));
-        //let mut dynam = image::DynamicImage::ImageRgb8(imgbuf);
-        imgbuf.save("test.jpg").unwrap();
+        let imgbuf = image_resample(&imgbuf, 128, 128);
 
-        let r_resize: Vec<u8> = read_i32_vector_file((PATH.to_owned() + RESIZE[0]).to_string());
-        let g_resize: Vec<u8> = read_i32_vector_file((PATH.to_owned() + RESIZE[1]).to_string());
-        let b_resize: Vec<u8> = read_i32_vector_file((PATH.to_owned() + RESIZE[2]).to_string());
+        let r_resize: Vec<u8> = read_u8_vector_file(PATH.to_owned() + RESIZE[0]);
+        let g_resize: Vec<u8> = read_u8_vector_file(PATH.to_owned() + RESIZE[1]);
+        let b_resize: Vec<u8> = read_u8_vector_file(PATH.to_owned() + RESIZE[2]);
+        assert_eq!(r_resize.len(), 128 * 128);
 
         for (pix, r, g, b) in izip!(imgbuf.enumerate_pixels(), r_resize, g_resize, b_resize) {
-            println!("xy: {:?} {:?}", pix.0, pix.1);
-            assert_eq!(pix.2[0], r);
-            assert_eq!(pix.2[1], g);
-            assert_eq!(pix.2[2], b);
+            assert_eq!(pix.2[0], r, "red at {:?}", (pix.0, pix.1));
+            assert_eq!(pix.2[1], g, "green at {:?}", (pix.0, pix.1));
+            assert_eq!(pix.2[2], b, "blue at {:?}", (pix.0, pix.1));
         }
     }
-}
\ No newline at end of file
+
+    /// Reads a comma separated dump of channel values.
+    fn read_u8_vector_file(filename: String) -> Vec<u8> {
+        fs::read_to_string(filename)
+            .unwrap()
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(|value| value.parse::<u8>().unwrap())
+            .collect()
+    }
+}
@@ -1,24 +1,81 @@
 use axum::{
-    extract::{Multipart, State},
-    http::{StatusCode, Uri},
+    extract::{DefaultBodyLimit, FromRef, MatchedPath, Multipart, Path, Query, Request, State},
+    http::{header, Method, StatusCode, Uri},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
     routing::{get, post},
     Json,
 };
-use image::{DynamicImage, ImageReader};
-use std::io::{Cursor, Error, ErrorKind};
-use tokio::{signal, task};
+use image::{DynamicImage, ImageFormat};
+use serde::{Deserialize, Serialize};
+use tokio::signal;
 
+use crate::config::{Config, QueryConfig};
+use crate::decode::Decoder;
+use crate::error::{Error, Result};
+use crate::iqdb::{AddStatus, PostId, Status, IQDB};
+use crate::metrics::{Stage, METRICS};
 use crate::signature::HaarSignature;
-use crate::{iqdb::IQDB, signature};
 
-pub async fn router() -> axum::Router {
-    let iqdb = IQDB::new().await;
+mod batch;
+
+/// State shared by the handlers, which extract the part they need.
+#[derive(Clone)]
+struct AppState {
+    iqdb: IQDB,
+    decoder: Decoder,
+    query: QueryConfig,
+}
+
+impl FromRef<AppState> for IQDB {
+    fn from_ref(state: &AppState) -> Self {
+        state.iqdb.clone()
+    }
+}
+
+impl FromRef<AppState> for Decoder {
+    fn from_ref(state: &AppState) -> Self {
+        state.decoder.clone()
+    }
+}
+
+impl FromRef<AppState> for QueryConfig {
+    fn from_ref(state: &AppState) -> Self {
+        state.query.clone()
+    }
+}
+
+/// Builds the routes around `iqdb`, which may still be loading. Routes that use the index
+/// respond with service unavailable (503) until it's loaded. Uploads and queries are held to the
+/// limits in `config`.
+pub fn app(iqdb: IQDB, config: &Config) -> axum::Router {
+    let body_limit = DefaultBodyLimit::max(config.limits.max_body_bytes);
+    let state = AppState {
+        iqdb: iqdb.clone(),
+        decoder: Decoder::new(config.limits.clone(), config.resampler),
+        query: config.query.clone(),
+    };
+    let index_routes = axum::Router::new()
+        .route("/images", post(batch::add_images))
+        .route("/import", post(batch::import))
+        .route("/images/:post_id", post(add_image).delete(remove_image))
+        .route("/query", post(query))
+        .route("/snapshot", post(save_snapshot))
+        .route_layer(middleware::from_fn_with_state(iqdb.clone(), require_ready))
+        .route_layer(middleware::from_fn(count_operations));
     axum::Router::new()
         .fallback(fallback)
         .route("/", get(hello))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/status", get(status))
+        .route("/metrics", get(metrics))
         .route("/upload", post(query_image))
-        .with_state(iqdb.unwrap())
+        .route("/signature", post(signature))
+        .route("/export", get(batch::export))
+        .merge(index_routes)
+        .layer(body_limit)
+        .with_state(state)
 }
 
 pub async fn shutdown_signal() {
@@ -45,10 +102,65 @@ pub async fn shutdown_signal() {
     }
 }
 
+/// Response body for "post /images/:post_id".
+#[derive(Serialize)]
+struct AddImageResponse {
+    post_id: PostId,
+    status: AddStatus,
+    hash: String,
+    signature: HaarSignature,
+}
+
+/// Response body for "delete /images/:post_id".
+#[derive(Serialize)]
+struct RemoveImageResponse {
+    post_id: PostId,
+}
+
+/// A single entry of the "post /query" response array.
+#[derive(Serialize)]
+struct QueryMatch {
+    post_id: PostId,
+    score: f32,
+    hash: String,
+    signature: HaarSignature,
+}
+
+/// Response body for "post /signature".
+#[derive(Serialize)]
+struct SignatureResponse {
+    hash: String,
+    signature: HaarSignature,
+    image: ImageMetadata,
+}
+
+/// The decoded image a signature was computed from.
+#[derive(Serialize)]
+struct ImageMetadata {
+    width: u32,
+    height: u32,
+    /// The file extension of the format, like "png" or "jpg".
+    format: &'static str,
+    /// Whether the image has no color, judged the way iqdb does, from the signature.
+    grayscale: bool,
+}
+
+/// Response body for "post /snapshot".
+#[derive(Serialize)]
+struct SnapshotResponse {
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct QueryParams {
+    limit: Option<usize>,
+    hash: Option<String>,
+}
+
 /// axum handler for any request that fails to match the router routes.
 /// this implementation returns http status code not found (404).
-async fn fallback(uri: Uri) -> impl IntoResponse {
-    (StatusCode::NOT_FOUND, format!("no route {}", uri))
+async fn fallback(uri: Uri) -> Error {
+    Error::NotFound(format!("no route {}", uri))
 }
 
 /// axum handler for "get /" which returns a string and causes axum to
@@ -57,50 +169,753 @@ async fn hello() -> &'static str {
     "hello, world!"
 }
 
-// Axum Route for ...
-async fn upload(State(iqdb): State<IQDB>) {}
+/// axum handler for "get /healthz", which responds ok as long as the server is up, even while the
+/// index is loading.
+async fn healthz() -> &'static str {
+    "ok"
+}
 
-// Handler
-async fn query_image(State(iqdb): State<IQDB>, multipart: Multipart) -> Response {
-    let res = match extract_image(multipart).await {
-        Ok(img) => img,
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+/// axum handler for "get /readyz", which responds with the progress of the index load, with
+/// status code ok once it's loaded and service unavailable (503) before.
+async fn readyz(State(iqdb): State<IQDB>) -> Response {
+    let status = iqdb.progress.status();
+    let code = match status.ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
     };
-    // Calculate the Haar Signature
-    let sig: HaarSignature = task::spawn_blocking(move || signature::HaarSignature::from(res))
-        .await
-        .expect("Error while generating haar signature");
-    // Insert into the db
-    let id = iqdb.sql.insert_signature(&sig).await;
+    (code, Json(status)).into_response()
+}
 
-    Json(sig).into_response()
+/// axum handler for "get /status", which responds with statistics about the index and the
+/// database. The index is swapped in once it's fully loaded, so until then its statistics are of
+/// an empty index, and `load` tells how far along loading is.
+async fn status(State(iqdb): State<IQDB>) -> Result<Json<Status>> {
+    Ok(Json(iqdb.status().await?))
+}
+
+/// axum handler for "get /metrics", which responds with the Prometheus metrics.
+async fn metrics() -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.render(),
+    )
+        .into_response()
+}
+
+/// Middleware counting queries, inserts and deletes, by whether they succeeded.
+async fn count_operations(request: Request, next: Next) -> Response {
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str());
+    let operation = match (request.method(), path) {
+        (&Method::POST, Some("/query")) => Some("query"),
+        (&Method::POST, Some("/images/:post_id")) => Some("insert"),
+        (&Method::POST, Some("/images")) => Some("batch_insert"),
+        (&Method::POST, Some("/import")) => Some("import"),
+        (&Method::DELETE, Some("/images/:post_id")) => Some("delete"),
+        _ => None,
+    };
+    let response = next.run(request).await;
+    if let Some(operation) = operation {
+        METRICS.count(operation, response.status().is_success());
+    }
+    response
 }
 
-async fn extract_image(mut multipart: Multipart) -> Result<DynamicImage, Error> {
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let raw_data = field.bytes().await.unwrap();
-        let read_image = ImageReader::new(Cursor::new(raw_data))
-            .with_guessed_format()
-            .expect("Error while unwrapping image.");
-        return Ok(read_image
-            .decode()
-            .expect("Error while decoding the image."));
+/// Middleware for the routes that use the index, which turns requests away while it's loading.
+async fn require_ready(State(iqdb): State<IQDB>, request: Request, next: Next) -> Response {
+    if !iqdb.progress.is_ready() {
+        let status = iqdb.progress.status();
+        return Error::Unavailable(format!(
+            "the index is still loading ({} of {} images)",
+            status.loaded, status.total
+        ))
+        .into_response();
     }
-    Err(Error::new(ErrorKind::InvalidInput, "No input found"))
+    next.run(request).await
+}
+
+/// axum handler for "post /images/:post_id", which adds the multipart `file` to the database
+/// under `post_id` and responds with the computed signature. Re-adding a post replaces its
+/// signature, and the response `status` tells if the post was `inserted` or `updated`.
+async fn add_image(
+    State(iqdb): State<IQDB>,
+    State(decoder): State<Decoder>,
+    Path(post_id): Path<PostId>,
+    multipart: Multipart,
+) -> Result<Json<AddImageResponse>> {
+    let sig = signature_from_multipart(&decoder, multipart).await?;
+    let status = iqdb.add_image(post_id, &sig).await?;
+    Ok(Json(AddImageResponse {
+        post_id,
+        status,
+        hash: sig.to_hash(),
+        signature: sig,
+    }))
+}
+
+/// axum handler for "delete /images/:post_id", which removes the post from the database.
+async fn remove_image(
+    State(iqdb): State<IQDB>,
+    Path(post_id): Path<PostId>,
+) -> Result<Json<RemoveImageResponse>> {
+    let post_id = iqdb.remove_image(post_id).await?;
+    Ok(Json(RemoveImageResponse { post_id }))
+}
+
+/// axum handler for "post /query?limit=N", which responds with the posts most similar to the
+/// multipart `file`, best match first. Instead of a file, a signature hash can be sent in the
+/// `hash` parameter or multipart field. A `limit` over the configured maximum is rejected.
+async fn query(
+    State(iqdb): State<IQDB>,
+    State(decoder): State<Decoder>,
+    State(limits): State<QueryConfig>,
+    Query(params): Query<QueryParams>,
+    multipart: Option<Multipart>,
+) -> Result<Json<Vec<QueryMatch>>> {
+    let limit = params.limit.unwrap_or(limits.default_limit);
+    if limit > limits.max_limit {
+        return Err(Error::Validation(format!(
+            "limit {} is over the maximum of {}",
+            limit, limits.max_limit
+        )));
+    }
+    let sig = query_signature(&decoder, params.hash, multipart).await?;
+
+    let mut matches: Vec<QueryMatch> = Vec::new();
+    for m in iqdb.query(&sig, limit).await? {
+        if let Some(signature) = iqdb.get_signature(m.id).await? {
+            matches.push(QueryMatch {
+                post_id: m.id,
+                score: m.score,
+                hash: signature.to_hash(),
+                signature,
+            });
+        }
+    }
+    Ok(Json(matches))
+}
+
+/// axum handler for "post /snapshot", which writes the index snapshot now instead of waiting for
+/// shutdown. Responds with not found (404) if snapshots aren't enabled.
+async fn save_snapshot(State(iqdb): State<IQDB>) -> Result<Json<SnapshotResponse>> {
+    match iqdb.save_snapshot().await? {
+        Some(path) => Ok(Json(SnapshotResponse {
+            path: path.display().to_string(),
+        })),
+        None => Err(Error::NotFound("snapshots are not enabled".to_string())),
+    }
+}
+
+/// axum handler for "post /upload", which responds with the signature of the multipart `file`.
+///
+/// Nothing is stored, like "post /signature". It used to add the image as well, which is what
+/// "post /images/:post_id" is for now.
+async fn query_image(
+    State(decoder): State<Decoder>,
+    multipart: Multipart,
+) -> Result<Json<HaarSignature>> {
+    Ok(Json(signature_from_multipart(&decoder, multipart).await?))
+}
+
+/// axum handler for "post /signature", which responds with the hash and signature of the
+/// multipart `file`, and what the decoded image looks like. Nothing is stored.
+async fn signature(
+    State(decoder): State<Decoder>,
+    multipart: Multipart,
+) -> Result<Json<SignatureResponse>> {
+    let (img, format) = extract_image(&decoder, multipart).await?;
+    let (width, height) = (img.width(), img.height());
+    let signature = decoder.signature(img).await?;
+    Ok(Json(SignatureResponse {
+        hash: signature.to_hash(),
+        image: ImageMetadata {
+            width,
+            height,
+            format: format
+                .extensions_str()
+                .first()
+                .copied()
+                .unwrap_or("unknown"),
+            grayscale: signature.is_grayscale(),
+        },
+        signature,
+    }))
+}
+
+async fn signature_from_multipart(
+    decoder: &Decoder,
+    multipart: Multipart,
+) -> Result<HaarSignature> {
+    let (img, _) = extract_image(decoder, multipart).await?;
+    decoder.signature(img).await
+}
+
+/// Reads the signature to query with from the `hash` parameter, or else from the `file` or
+/// `hash` field of the multipart form.
+async fn query_signature(
+    decoder: &Decoder,
+    hash: Option<String>,
+    multipart: Option<Multipart>,
+) -> Result<HaarSignature> {
+    if let Some(hash) = hash {
+        return Ok(hash.parse()?);
+    }
+    if let Some(mut multipart) = multipart {
+        while let Some(field) = multipart.next_field().await? {
+            match field.name() {
+                Some("file") => {
+                    let timer = METRICS.time(Stage::Extract);
+                    let bytes = field.bytes().await?;
+                    drop(timer);
+                    let img = decoder.decode(bytes).await?;
+                    return decoder.signature(img).await;
+                }
+                Some("hash") => return Ok(field.text().await?.parse()?),
+                _ => continue,
+            }
+        }
+    }
+    Err(Error::Validation(
+        "a file or hash to query with is required".to_string(),
+    ))
+}
+
+/// Decodes the `file` field of the multipart form, and tells which format it was in.
+async fn extract_image(
+    decoder: &Decoder,
+    mut multipart: Multipart,
+) -> Result<(DynamicImage, ImageFormat)> {
+    while let Some(field) = multipart.next_field().await? {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let timer = METRICS.time(Stage::Extract);
+        let bytes = field.bytes().await?;
+        drop(timer);
+        return decoder.decode_with_format(bytes).await;
+    }
+    Err(Error::Validation("a file field is required".to_string()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use axum_test::multipart::{MultipartForm, Part};
     use axum_test::TestServer;
+    use image::{ImageFormat, Rgb, RgbImage};
+    use std::io::Cursor;
+
+    async fn router() -> axum::Router {
+        let config = Config::for_tests();
+        app(IQDB::new(&config).await.unwrap(), &config)
+    }
+
+    /// Encodes a small, low contrast test pattern as a png.
+    fn test_png(seed: u8) -> Vec<u8> {
+        let img = RgbImage::from_fn(64, 64, |x, y| {
+            Rgb([
+                100 + ((x as u8 + seed) % 16),
+                120 + ((y as u8 + seed) % 8),
+                90 + ((x + y) as u8 % 10),
+            ])
+        });
+        let mut buf = Cursor::new(Vec::new());
+        img.write_to(&mut buf, ImageFormat::Png).unwrap();
+        buf.into_inner()
+    }
+
+    /// A well-formed hash with a coefficient that has no bucket in the index.
+    fn out_of_range_hash() -> String {
+        let mut sig = crate::HaarSignature::new();
+        sig.sig0.sig = [16384; crate::signature::haar::NUM_COEFS];
+        sig.to_hash()
+    }
+
+    fn file_form(seed: u8) -> MultipartForm {
+        MultipartForm::new().add_part(
+            "file",
+            Part::bytes(test_png(seed))
+                .file_name("test.png")
+                .mime_type("image/png"),
+        )
+    }
 
     #[tokio::test]
     async fn route_tests() {
         let server = TestServer::new(router().await).unwrap();
 
-        let response = server.get(&"/").await;
+        let response = server.get("/").await;
 
         response.assert_status_ok();
         response.assert_text("hello, world!");
     }
+
+    #[tokio::test]
+    async fn signature_route() {
+        let server = TestServer::new(router().await).unwrap();
+        let images = || async {
+            let status = server.get("/status").await.json::<serde_json::Value>();
+            status["index"]["images"].as_u64().unwrap()
+        };
+        let before = images().await;
+
+        let response = server.post("/signature").multipart(file_form(5)).await;
+        response.assert_status_ok();
+        let body = response.json::<serde_json::Value>();
+        let img = image::load_from_memory(&test_png(5)).unwrap();
+        assert_eq!(body["hash"], HaarSignature::from(img).to_hash());
+        assert_eq!(body["signature"]["sig"].as_array().unwrap().len(), 3);
+        assert_eq!(body["image"]["width"], 64);
+        assert_eq!(body["image"]["height"], 64);
+        assert_eq!(body["image"]["format"], "png");
+        assert!(body["image"]["grayscale"].is_boolean());
+
+        // Neither route stores anything
+        server
+            .post("/upload")
+            .multipart(file_form(5))
+            .await
+            .assert_status_ok();
+        assert_eq!(images().await, before);
+
+        let response = server.post("/signature").await;
+        response.assert_status_bad_request();
+    }
+
+    #[tokio::test]
+    async fn image_routes() {
+        let server = TestServer::new(router().await).unwrap();
+        let _ = server.delete("/images/999998").await;
+
+        let response = server.post("/images/999998").multipart(file_form(3)).await;
+        response.assert_status_ok();
+        let body = response.json::<serde_json::Value>();
+        assert_eq!(body["post_id"], 999998);
+        assert_eq!(body["status"], "inserted");
+        let hash = body["hash"].as_str().unwrap().to_owned();
+        assert!(hash.starts_with("iqdb_"));
+        assert_eq!(body["signature"]["avglf"].as_array().unwrap().len(), 3);
+        assert_eq!(body["signature"]["sig"].as_array().unwrap().len(), 3);
+
+        let response = server
+            .post("/query")
+            .add_query_param("limit", 1)
+            .multipart(file_form(3))
+            .await;
+        response.assert_status_ok();
+        let body = response.json::<serde_json::Value>();
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["post_id"], 999998);
+        assert_eq!(body[0]["hash"], hash);
+        assert!((body[0]["score"].as_f64().unwrap() - 100.0).abs() < 1e-3);
+
+        // Querying by hash finds the same post, as a parameter or a multipart field
+        let response = server
+            .post("/query")
+            .add_query_param("limit", 1)
+            .add_query_param("hash", &hash)
+            .await;
+        response.assert_status_ok();
+        assert_eq!(response.json::<serde_json::Value>()[0]["post_id"], 999998);
+
+        let response = server
+            .post("/query")
+            .add_query_param("limit", 1)
+            .multipart(MultipartForm::new().add_text("hash", hash.clone()))
+            .await;
+        response.assert_status_ok();
+        assert_eq!(response.json::<serde_json::Value>()[0]["post_id"], 999998);
+
+        // Re-adding the post replaces its signature instead of adding a second entry
+        let response = server.post("/images/999998").multipart(file_form(7)).await;
+        response.assert_status_ok();
+        assert_eq!(response.json::<serde_json::Value>()["status"], "updated");
+
+        let response = server.post("/query").multipart(file_form(7)).await;
+        let body = response.json::<serde_json::Value>();
+        let matches = body.as_array().unwrap();
+        assert_eq!(matches[0]["post_id"], 999998);
+        assert_eq!(matches.iter().filter(|m| m["post_id"] == 999998).count(), 1);
+
+        let response = server.delete("/images/999998").await;
+        response.assert_status_ok();
+        response.assert_json(&serde_json::json!({ "post_id": 999998 }));
+
+        let response = server.delete("/images/999998").await;
+        response.assert_status_not_found();
+        response.assert_json(&serde_json::json!({
+            "error": "not_found",
+            "message": "no post 999998",
+        }));
+    }
+
+    #[tokio::test]
+    async fn batch_routes() {
+        let server = TestServer::new(router().await).unwrap();
+        for post_id in [999990, 999992] {
+            let _ = server.delete(&format!("/images/{}", post_id)).await;
+        }
+        let png = |seed| {
+            Part::bytes(test_png(seed))
+                .file_name("test.png")
+                .mime_type("image/png")
+        };
+
+        let form = MultipartForm::new()
+            .add_text("post_id", "999990")
+            .add_part("file", png(8))
+            .add_text("post_id", "999991")
+            .add_part(
+                "file",
+                Part::bytes(b"garbage".to_vec()).file_name("bad.png"),
+            )
+            .add_text("post_id", "x")
+            .add_part("file", png(8))
+            .add_part("file", png(8))
+            .add_text("post_id", "999992")
+            .add_text("hash", out_of_range_hash());
+        let response = server.post("/images").multipart(form).await;
+        response.assert_status_ok();
+        let body = response.json::<serde_json::Value>();
+        assert_eq!(
+            (body["inserted"].as_u64(), body["failed"].as_u64()),
+            (Some(1), Some(4))
+        );
+        let items = body["items"].as_array().unwrap();
+        assert_eq!(items[0]["post_id"], 999990);
+        assert_eq!(items[0]["status"], "inserted");
+        let hash = items[0]["hash"].as_str().unwrap().to_owned();
+        assert_eq!(items[1]["post_id"], 999991);
+        assert_eq!(items[1]["error"], "decode");
+        assert_eq!(items[2]["post_id"], serde_json::Value::Null);
+        assert_eq!(items[2]["error"], "validation");
+        assert_eq!(items[3]["error"], "validation");
+        assert_eq!(items[4]["post_id"], 999992);
+        assert!(items[4]["message"]
+            .as_str()
+            .unwrap()
+            .contains("out of range"));
+
+        let ndjson = format!(
+            "{{\"post_id\": 999990, \"hash\": \"{hash}\"}}\n\
+             not json\n\
+             \n\
+             {{\"post_id\": 999993, \"hash\": \"iqdb_12\"}}\n\
+             {{\"post_id\": 999992, \"hash\": \"{hash}\"}}"
+        );
+        let response = server
+            .post("/images")
+            .bytes(ndjson.into())
+            .content_type("application/x-ndjson")
+            .await;
+        response.assert_status_ok();
+        let body = response.json::<serde_json::Value>();
+        assert_eq!(body["updated"], 1);
+        assert_eq!(body["inserted"], 1);
+        assert_eq!(body["failed"], 2);
+        let items = body["items"].as_array().unwrap();
+        assert_eq!(
+            items
+                .iter()
+                .map(|i| i["index"].as_u64().unwrap())
+                .collect::<Vec<_>>(),
+            [0, 1, 2, 3]
+        );
+        assert_eq!(items[0]["status"], "updated");
+        assert_eq!(items[1]["error"], "validation");
+        assert_eq!(items[2]["post_id"], 999993);
+        // Not stored by the out of range hash above
+        assert_eq!(items[3]["status"], "inserted");
+
+        let response = server
+            .post("/query")
+            .add_query_param("hash", &hash)
+            .add_query_param("limit", 2)
+            .await;
+        let mut found: Vec<u64> = response
+            .json::<Vec<serde_json::Value>>()
+            .iter()
+            .map(|m| m["post_id"].as_u64().unwrap())
+            .collect();
+        found.sort();
+        assert_eq!(found, [999990, 999992]);
+
+        let response = server.post("/images").text("999990").await;
+        response.assert_status_bad_request();
+
+        for post_id in [999990, 999992] {
+            server
+                .delete(&format!("/images/{}", post_id))
+                .await
+                .assert_status_ok();
+        }
+    }
+
+    #[tokio::test]
+    async fn export_and_import() {
+        let server = TestServer::new(router().await).unwrap();
+        let _ = server.delete("/images/999980").await;
+        server
+            .post("/images/999980")
+            .multipart(file_form(9))
+            .await
+            .assert_status_ok();
+
+        let response = server.get("/export").await;
+        response.assert_status_ok();
+        assert_eq!(response.header("content-type"), "application/x-ndjson");
+        let records: Vec<crate::export::ImageRecord> = response
+            .text()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let record = records.iter().find(|r| r.post_id == 999980).unwrap();
+        assert!(record.avglf.is_some());
+        let line = serde_json::to_string(record).unwrap();
+
+        server.delete("/images/999980").await.assert_status_ok();
+        let _ = server.delete("/images/999979").await;
+        let tampered = line.replace("\"grayscale\":false", "\"grayscale\":true");
+        let out_of_range = format!(
+            r#"{{"post_id": 999979, "hash": "{}"}}"#,
+            out_of_range_hash()
+        );
+        let response = server
+            .post("/import")
+            .text(format!("{line}\n{tampered}\n{out_of_range}\n"))
+            .await;
+        response.assert_status_ok();
+        let body = response.json::<serde_json::Value>();
+        assert_eq!(body["inserted"], 1);
+        assert_eq!(body["failed"], 2);
+        assert_eq!(body["items"][1]["error"], "validation");
+        assert_eq!(body["items"][2]["error"], "validation");
+        let message = body["items"][2]["message"].as_str().unwrap();
+        assert!(message.contains("out of range"), "{message}");
+        // Nothing was stored for the rejected post
+        server
+            .delete("/images/999979")
+            .await
+            .assert_status_not_found();
+
+        let response = server
+            .post("/query")
+            .add_query_param("hash", &record.hash)
+            .add_query_param("limit", 1)
+            .await;
+        assert_eq!(response.json::<serde_json::Value>()[0]["post_id"], 999980);
+
+        server.delete("/images/999980").await.assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn upload_limits() {
+        let mut config = Config::for_tests();
+        config.limits.max_body_bytes = 64 * 1024;
+        config.limits.max_width = 32;
+        let server = TestServer::new(app(IQDB::new(&config).await.unwrap(), &config)).unwrap();
+
+        // The 64x64 test image is over the width limit
+        let response = server.post("/upload").multipart(file_form(1)).await;
+        response.assert_status_unprocessable_entity();
+        assert_eq!(response.json::<serde_json::Value>()["error"], "limit");
+
+        let big = MultipartForm::new().add_part(
+            "file",
+            Part::bytes(vec![0; 128 * 1024])
+                .file_name("big.png")
+                .mime_type("image/png"),
+        );
+        let response = server.post("/upload").multipart(big).await;
+        response.assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(response.json::<serde_json::Value>()["error"], "too_large");
+
+        // A batch that breaks off still reports the records added before it
+        let mut sig = crate::HaarSignature::new();
+        for (i, s) in sig.sig0.sig.iter_mut().enumerate() {
+            *s = i as i16 + 1;
+        }
+        sig.sig1 = sig.sig0.clone();
+        sig.sig2 = sig.sig0.clone();
+        let _ = server.delete("/images/999995").await;
+        let ndjson = format!(
+            "{{\"post_id\": 999995, \"hash\": \"{}\"}}\n{}",
+            sig.to_hash(),
+            "x".repeat(128 * 1024)
+        );
+        let response = server
+            .post("/images")
+            .bytes(ndjson.into())
+            .content_type("application/x-ndjson")
+            .await;
+        response.assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+        let body = response.json::<serde_json::Value>();
+        assert_eq!(body["error"], "too_large");
+        assert_eq!(body["inserted"], 1);
+        assert_eq!(body["items"][0]["post_id"], 999995);
+        server.delete("/images/999995").await.assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn bad_image_is_rejected() {
+        let server = TestServer::new(router().await).unwrap();
+        // A JPEG header followed by garbage
+        let form = || {
+            MultipartForm::new().add_part(
+                "file",
+                Part::bytes(b"\xff\xd8\xff\xe0garbage".to_vec())
+                    .file_name("bad.jpg")
+                    .mime_type("image/jpeg"),
+            )
+        };
+
+        for path in ["/images/999996", "/query", "/upload", "/signature"] {
+            let response = server.post(path).multipart(form()).await;
+            response.assert_status_unprocessable_entity();
+            assert_eq!(response.json::<serde_json::Value>()["error"], "decode");
+        }
+        server
+            .delete("/images/999996")
+            .await
+            .assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn unavailable_while_loading() {
+        let config = Config::for_tests();
+        let iqdb = IQDB::connect(&config).await.unwrap();
+        let server = TestServer::new(app(iqdb.clone(), &config)).unwrap();
+
+        server.get("/healthz").await.assert_status_ok();
+        let response = server.get("/readyz").await;
+        response.assert_status_service_unavailable();
+        assert_eq!(response.json::<serde_json::Value>()["ready"], false);
+        server
+            .post("/query")
+            .multipart(file_form(1))
+            .await
+            .assert_status_service_unavailable();
+        server
+            .delete("/images/999997")
+            .await
+            .assert_status_service_unavailable();
+        // The index is only swapped in once it's loaded
+        let body = server.get("/status").await.json::<serde_json::Value>();
+        assert_eq!(body["load"]["ready"], false);
+        assert_eq!(body["index"]["images"], 0);
+
+        iqdb.load().await.unwrap();
+        let response = server.get("/readyz").await;
+        response.assert_status_ok();
+        let body = response.json::<serde_json::Value>();
+        assert_eq!(body["ready"], true);
+        assert_eq!(body["loaded"], body["total"]);
+        server
+            .post("/query")
+            .multipart(file_form(1))
+            .await
+            .assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn status_route() {
+        let server = TestServer::new(router().await).unwrap();
+
+        let response = server.get("/status").await;
+        response.assert_status_ok();
+        let body = response.json::<serde_json::Value>();
+        assert_eq!(body["load"]["ready"], true);
+        assert!(body["index"]["images"].is_number());
+        assert_eq!(
+            body["index"]["largest_buckets"].as_array().unwrap().len(),
+            6
+        );
+        assert!(body["storage"]["file_bytes"].as_i64().unwrap() > 0);
+        assert_eq!(body["storage"]["schema_version"], 4);
+    }
+
+    #[tokio::test]
+    async fn metrics_route() {
+        let server = TestServer::new(router().await).unwrap();
+        server
+            .post("/query")
+            .add_query_param("hash", "iqdb_1234")
+            .await
+            .assert_status_bad_request();
+        server
+            .post("/query")
+            .multipart(file_form(5))
+            .await
+            .assert_status_ok();
+
+        let response = server.get("/metrics").await;
+        response.assert_status_ok();
+        let text = response.text();
+        assert!(text.contains(r#"oiqdb_operations_total{operation="query",outcome="error"}"#));
+        assert!(text.contains(r#"oiqdb_operations_total{operation="query",outcome="ok"}"#));
+        for stage in [
+            "extract",
+            "decode",
+            "resize",
+            "transform_char",
+            "calc_haar",
+            "scoring",
+            "top_k",
+        ] {
+            assert!(text.contains(&format!(
+                r#"oiqdb_stage_duration_seconds_count{{stage="{}"}}"#,
+                stage
+            )));
+        }
+        assert!(text.contains(r#"oiqdb_index_lock_wait_seconds_count{mode="read"}"#));
+    }
+
+    #[tokio::test]
+    async fn snapshot_needs_path() {
+        // SNAPSHOT_PATH isn't set in the test environment
+        let server = TestServer::new(router().await).unwrap();
+
+        server.post("/snapshot").await.assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn query_rejects_bad_hash() {
+        let server = TestServer::new(router().await).unwrap();
+
+        let response = server
+            .post("/query")
+            .add_query_param("hash", "iqdb_1234")
+            .await;
+        response.assert_status_bad_request();
+
+        // A coefficient with no bucket in the index
+        let mut sig = crate::HaarSignature::new();
+        sig.sig0.sig = [i16::MIN; crate::signature::haar::NUM_COEFS];
+        let response = server
+            .post("/query")
+            .add_query_param("hash", sig.to_hash())
+            .await;
+        response.assert_status_bad_request();
+        assert!(response.text().contains("out of range"));
+
+        let response = server
+            .post("/query")
+            .add_query_param("limit", 1_000_000)
+            .await;
+        response.assert_status_bad_request();
+        assert_eq!(response.json::<serde_json::Value>()["error"], "validation");
+    }
+
+    #[tokio::test]
+    async fn query_requires_file() {
+        let server = TestServer::new(router().await).unwrap();
+
+        let response = server
+            .post("/query")
+            .multipart(MultipartForm::new().add_text("limit", "1"))
+            .await;
+        response.assert_status_bad_request();
+    }
 }
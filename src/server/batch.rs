@@ -0,0 +1,110 @@
+use axum::{
+    body::Body,
+    extract::{FromRequest, Multipart, Request, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::batch::{Batch, Input};
+use crate::decode::Decoder;
+use crate::error::{Error, Result};
+use crate::export;
+use crate::iqdb::{PostId, IQDB};
+
+/// axum handler for "post /images", which adds many posts in one request. The body is either
+/// multipart, with each `file` or `hash` field preceded by a `post_id` field, or NDJSON
+/// (`application/x-ndjson`) with one `{"post_id": 1, "hash": "iqdb_..."}` record per line.
+///
+/// Records are added in chunks as the body is read, see [`Batch`], so NDJSON bodies aren't held
+/// to the body size limit, and a body that breaks off keeps the chunks before it. The response
+/// reports on every record, see [`respond`].
+pub(super) async fn add_images(
+    State(iqdb): State<IQDB>,
+    State(decoder): State<Decoder>,
+    request: Request,
+) -> Result<Response> {
+    let content_type = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let mut batch = Batch::new(&iqdb, &decoder);
+    let read = if content_type.starts_with("multipart/form-data") {
+        let multipart = Multipart::from_request(request, &())
+            .await
+            .map_err(|e| Error::Validation(e.body_text()))?;
+        read_multipart(&mut batch, multipart).await
+    } else if content_type.starts_with("application/x-ndjson") {
+        batch
+            .push_ndjson(request.into_body().into_data_stream())
+            .await
+    } else {
+        return Err(Error::Validation(
+            "expected a multipart/form-data or application/x-ndjson body".to_string(),
+        ));
+    };
+    Ok(respond(batch, read).await)
+}
+
+/// Responds with the report of `batch`. If the body broke off, the status is the one of the
+/// error, which is in the body next to the records that were added before it, like
+/// `{"error": "too_large", "message": "...", "inserted": 3, ...}`.
+async fn respond(batch: Batch<'_>, read: Result<()>) -> Response {
+    let status = match &read {
+        Ok(()) => StatusCode::OK,
+        Err(e) => e.status(),
+    };
+    (status, Json(batch.finish_after(read).await)).into_response()
+}
+
+async fn read_multipart(batch: &mut Batch<'_>, mut multipart: Multipart) -> Result<()> {
+    let mut post_id: Option<Result<PostId>> = None;
+    while let Some(field) = multipart.next_field().await? {
+        let input = match field.name() {
+            Some("post_id") => {
+                let text = field.text().await?;
+                post_id = Some(text.trim().parse().map_err(|_| {
+                    Error::Validation(format!("post_id \"{}\" is not a number", text))
+                }));
+                continue;
+            }
+            Some("file") => Input::File(field.bytes().await?),
+            Some("hash") => Input::Hash(field.text().await?),
+            _ => continue,
+        };
+        let record = match post_id.take() {
+            Some(post_id) => post_id.map(|post_id| (post_id, input)),
+            None => Err(Error::Validation(
+                "each file or hash needs a post_id field before it".to_string(),
+            )),
+        };
+        batch.push(record).await;
+    }
+    Ok(())
+}
+
+/// axum handler for "get /export", which streams every stored post as NDJSON, see
+/// [`export::export`].
+pub(super) async fn export(State(iqdb): State<IQDB>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(export::export(iqdb.sql.clone())),
+    )
+        .into_response()
+}
+
+/// axum handler for "post /import", which adds every record of an NDJSON export, whatever the
+/// content type. Responds like "post /images".
+pub(super) async fn import(
+    State(iqdb): State<IQDB>,
+    State(decoder): State<Decoder>,
+    request: Request,
+) -> Response {
+    let mut batch = Batch::new(&iqdb, &decoder);
+    let read = batch
+        .push_ndjson(request.into_body().into_data_stream())
+        .await;
+    respond(batch, read).await
+}
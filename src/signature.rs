@@ -1,10 +1,161 @@
 use image::imageops::FilterType;
-use image::DynamicImage;
-use serde::Serialize;
+use image::{DynamicImage, RgbImage};
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use serde_with::DeserializeFromStr;
+use std::fmt;
+
+use crate::error::Error;
+use crate::metrics::{Stage, METRICS};
+use crate::resize::image_resample;
 use std::ops::Index;
+use std::str::FromStr;
 
 pub mod haar;
 
+/// How an image is scaled down to NUM_PIXELS x NUM_PIXELS before its signature is computed.
+///
+/// Named in settings by [`Resampler::NAMES`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, DeserializeFromStr)]
+pub enum Resampler {
+    /// The libgd bilinear resampler used by the C++ iqdb, so signatures match the original.
+    #[default]
+    Gd,
+    /// One of the `image` crate's filters. Faster, but signatures differ slightly from the C++ iqdb.
+    Filter(FilterType),
+}
+
+impl Resampler {
+    /// The name of each resampler, as accepted by [`Resampler::from_str`].
+    pub const NAMES: [(&'static str, Resampler); 6] = [
+        ("gd", Resampler::Gd),
+        ("nearest", Resampler::Filter(FilterType::Nearest)),
+        ("triangle", Resampler::Filter(FilterType::Triangle)),
+        ("catmullrom", Resampler::Filter(FilterType::CatmullRom)),
+        ("gaussian", Resampler::Filter(FilterType::Gaussian)),
+        ("lanczos3", Resampler::Filter(FilterType::Lanczos3)),
+    ];
+}
+
+impl fmt::Display for Resampler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, _) = Resampler::NAMES
+            .iter()
+            .find(|(_, resampler)| resampler == self)
+            .expect("every resampler has a name");
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Resampler {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Resampler::NAMES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s))
+            .map(|&(_, resampler)| resampler)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Resampler::NAMES.iter().map(|(name, _)| *name).collect();
+                format!(
+                    "unknown resampler \"{}\", expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// Version byte that leads every blob written by [`HaarSignature::sig_to_bytes`].
+pub const SIG_BLOB_VERSION: u8 = 1;
+/// Length of a version 1 blob: the version byte, then 3 x NUM_COEFS little-endian i16.
+pub const SIG_BLOB_LEN: usize = 1 + haar::N_COLORS * haar::NUM_COEFS * 2;
+
+/// Errors from decoding a signature blob.
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// The blob is empty, or its length doesn't match its version.
+    Length(usize),
+    /// The blob was written with an encoding version this build can't read.
+    Version(u8),
+    /// The blob holds a value the C++ iqdb can't have computed.
+    Invalid(InvalidSignature),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Length(len) => write!(
+                f,
+                "signature blob is {} bytes, expected {}",
+                len, SIG_BLOB_LEN
+            ),
+            DecodeError::Version(version) => {
+                write!(f, "unsupported signature blob version {}", version)
+            }
+            DecodeError::Invalid(e) => write!(f, "invalid signature blob: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// A value in a signature that [`haar::calc_haar`] can't have computed, found by
+/// [`HaarSignature::validate`].
+#[derive(Debug, PartialEq)]
+pub enum InvalidSignature {
+    /// An average luminance that isn't a finite number.
+    Avgl(f64),
+    /// The DC coefficient (0), or a coefficient with no bucket in the index.
+    Coef(i16),
+}
+
+impl fmt::Display for InvalidSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidSignature::Avgl(avgl) => write!(f, "avglf {} is not a number", avgl),
+            InvalidSignature::Coef(coef) => write!(f, "coefficient {} is out of range", coef),
+        }
+    }
+}
+
+impl std::error::Error for InvalidSignature {}
+
+/// Prefix of a textual signature hash.
+pub const HASH_PREFIX: &str = "iqdb_";
+/// Number of hex digits after the prefix: 3 avglf doubles, then 3 x NUM_COEFS int16.
+pub const HASH_DIGITS: usize = 2 * (haar::N_COLORS * 8 + haar::N_COLORS * haar::NUM_COEFS * 2);
+
+/// Errors from parsing a textual signature hash.
+#[derive(Debug, PartialEq)]
+pub enum ParseHashError {
+    /// The hash doesn't start with `iqdb_`.
+    Prefix,
+    /// The hash doesn't have the expected number of hex digits.
+    Length(usize),
+    /// The hash has a character that isn't a hex digit.
+    Hex(hex::FromHexError),
+    /// The hash holds a value the C++ iqdb can't have computed.
+    Invalid(InvalidSignature),
+}
+
+impl fmt::Display for ParseHashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseHashError::Prefix => write!(f, "signature hash must start with {}", HASH_PREFIX),
+            ParseHashError::Length(len) => write!(
+                f,
+                "signature hash has {} hex digits, expected {}",
+                len, HASH_DIGITS
+            ),
+            ParseHashError::Hex(e) => write!(f, "invalid signature hash: {}", e),
+            ParseHashError::Invalid(e) => write!(f, "invalid signature hash: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ParseHashError {}
+
 pub enum SigIndex {
     S0,
     S1,
@@ -22,7 +173,7 @@ impl From<usize> for SigIndex {
     }
 }
 
-#[derive(Debug, Default, Serialize)]
+#[derive(Clone, Debug, Default)]
 pub struct HaarSignature {
     pub avglf: haar::Lumin,
     pub sig0: haar::SigT,
@@ -31,6 +182,7 @@ pub struct HaarSignature {
 }
 
 impl HaarSignature {
+    /// An all zero signature, as a starting point to fill in.
     pub fn new() -> Self {
         Self {
             avglf: [0.0; haar::N_COLORS],
@@ -51,6 +203,138 @@ impl HaarSignature {
             3
         }
     }
+
+    /// Checks that every value could have been computed by [`haar::calc_haar`].
+    ///
+    /// The index trusts coefficients to point at a bucket, so signatures that weren't computed
+    /// here are checked before they're used.
+    pub fn validate(&self) -> Result<(), InvalidSignature> {
+        if let Some(&avgl) = self.avglf.iter().find(|avgl| !avgl.is_finite()) {
+            return Err(InvalidSignature::Avgl(avgl));
+        }
+        for c in 0..haar::N_COLORS {
+            // The DC coefficient is never part of a signature
+            if let Some(&coef) = self[c].iter().find(|&&coef| {
+                coef == 0 || coef.unsigned_abs() as usize >= haar::NUM_PIXELS_SQUARED
+            }) {
+                return Err(InvalidSignature::Coef(coef));
+            }
+        }
+        Ok(())
+    }
+
+    /// Encodes the coefficients as a versioned, little-endian blob.
+    ///
+    /// The average luminance isn't part of the blob, it's stored next to it.
+    pub fn sig_to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(SIG_BLOB_LEN);
+        bytes.push(SIG_BLOB_VERSION);
+        for c in 0..haar::N_COLORS {
+            for coef in self[c] {
+                bytes.extend_from_slice(&coef.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// Encodes the signature as a hash string, the same way as danbooru iqdb.
+    ///
+    /// That's `iqdb_`, then the bits of the 3 avglf doubles as 16 hex digits each, and the 3 x
+    /// NUM_COEFS coefficients as 4 hex digits each, all big-endian.
+    pub fn to_hash(&self) -> String {
+        let mut bytes: Vec<u8> = Vec::with_capacity(HASH_DIGITS / 2);
+        for avgl in self.avglf {
+            bytes.extend_from_slice(&avgl.to_bits().to_be_bytes());
+        }
+        for c in 0..haar::N_COLORS {
+            for coef in self[c] {
+                bytes.extend_from_slice(&coef.to_be_bytes());
+            }
+        }
+        HASH_PREFIX.to_owned() + &hex::encode(bytes)
+    }
+
+    /// Decodes a blob written by [`HaarSignature::sig_to_bytes`], combining it with `avglf`.
+    ///
+    /// Fails with [`DecodeError::Invalid`] unless the result passes [`HaarSignature::validate`].
+    pub fn from_bytes(avglf: haar::Lumin, bytes: &[u8]) -> Result<Self, DecodeError> {
+        match bytes.first() {
+            Some(&SIG_BLOB_VERSION) if bytes.len() == SIG_BLOB_LEN => {}
+            Some(&SIG_BLOB_VERSION) | None => return Err(DecodeError::Length(bytes.len())),
+            Some(&version) => return Err(DecodeError::Version(version)),
+        }
+
+        let mut coefs = bytes[1..]
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]));
+        let mut sigs: [haar::SigT; haar::N_COLORS] = Default::default();
+        for sig in sigs.iter_mut() {
+            for (s, coef) in sig.sig.iter_mut().zip(coefs.by_ref()) {
+                *s = coef;
+            }
+        }
+        let [sig0, sig1, sig2] = sigs;
+        let signature = HaarSignature {
+            avglf,
+            sig0,
+            sig1,
+            sig2,
+        };
+        signature.validate().map_err(DecodeError::Invalid)?;
+        Ok(signature)
+    }
+}
+
+/// Serializes as `{"avglf": [...], "sig": [[...], [...], [...]]}`, the shape used by danbooru iqdb.
+impl Serialize for HaarSignature {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("HaarSignature", 2)?;
+        state.serialize_field("avglf", &self.avglf)?;
+        state.serialize_field(
+            "sig",
+            &[&self.sig0.sig[..], &self.sig1.sig[..], &self.sig2.sig[..]],
+        )?;
+        state.end()
+    }
+}
+
+/// Parses a hash written by [`HaarSignature::to_hash`] or by danbooru iqdb.
+///
+/// Fails with [`ParseHashError::Invalid`] unless the result passes [`HaarSignature::validate`],
+/// so a hash from a client can't point outside the index.
+impl FromStr for HaarSignature {
+    type Err = ParseHashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s.strip_prefix(HASH_PREFIX).ok_or(ParseHashError::Prefix)?;
+        if digits.len() != HASH_DIGITS {
+            return Err(ParseHashError::Length(digits.len()));
+        }
+        let bytes = hex::decode(digits).map_err(ParseHashError::Hex)?;
+        let (avgl_bytes, coef_bytes) = bytes.split_at(haar::N_COLORS * 8);
+
+        let mut signature = HaarSignature::default();
+        for (avgl, b) in signature.avglf.iter_mut().zip(avgl_bytes.chunks_exact(8)) {
+            *avgl = f64::from_bits(u64::from_be_bytes(b.try_into().unwrap()));
+        }
+        let mut coefs = coef_bytes
+            .chunks_exact(2)
+            .map(|b| i16::from_be_bytes([b[0], b[1]]));
+        for sig in [
+            &mut signature.sig0,
+            &mut signature.sig1,
+            &mut signature.sig2,
+        ] {
+            for (s, coef) in sig.sig.iter_mut().zip(coefs.by_ref()) {
+                *s = coef;
+            }
+        }
+        signature.validate().map_err(ParseHashError::Invalid)?;
+        Ok(signature)
+    }
 }
 
 impl Index<SigIndex> for HaarSignature {
@@ -77,12 +361,17 @@ impl Index<usize> for HaarSignature {
     }
 }
 
-impl From<DynamicImage> for HaarSignature {
-    #[inline]
-    fn from(filecontent: DynamicImage) -> Self {
-        let filecontent = resize_image(filecontent);
-        // Resize image and conver to YIQ
+impl HaarSignature {
+    /// Computes the signature of `filecontent`, resizing it with `resampler`.
+    pub fn from_image(filecontent: DynamicImage, resampler: Resampler) -> Self {
+        let timer = METRICS.time(Stage::Resize);
+        let filecontent = resize_image(filecontent, resampler);
+        drop(timer);
+        // Convert to YIQ
+        let timer = METRICS.time(Stage::Transform);
         let (a, b, c) = haar::transform_char(filecontent);
+        drop(timer);
+        let _timer = METRICS.time(Stage::CalcHaar);
         let (avglf, sig0, sig1, sig2): (haar::Lumin, haar::SigT, haar::SigT, haar::SigT) =
             haar::calc_haar(a, b, c);
         HaarSignature {
@@ -92,10 +381,41 @@ impl From<DynamicImage> for HaarSignature {
             sig2,
         }
     }
+
+    /// Computes the signature of a `width` x `height` image from its raw pixels, 3 bytes per
+    /// pixel in RGB order, row by row.
+    ///
+    /// Fails with [`Error::Validation`] if `pixels` isn't exactly that long.
+    pub fn from_rgb(width: u32, height: u32, pixels: Vec<u8>) -> crate::Result<Self> {
+        let expected = width as usize * height as usize * 3;
+        if expected == 0 || pixels.len() != expected {
+            return Err(Error::Validation(format!(
+                "expected {} bytes of RGB pixels for a {}x{} image, got {}",
+                expected,
+                width,
+                height,
+                pixels.len()
+            )));
+        }
+        let img = RgbImage::from_raw(width, height, pixels).expect("the length was checked");
+        Ok(HaarSignature::from(DynamicImage::ImageRgb8(img)))
+    }
 }
 
-fn resize_image(img: DynamicImage) -> DynamicImage {
-    img.resize_exact(128, 128, FilterType::Triangle)
+impl From<DynamicImage> for HaarSignature {
+    #[inline]
+    fn from(filecontent: DynamicImage) -> Self {
+        HaarSignature::from_image(filecontent, Resampler::default())
+    }
+}
+
+fn resize_image(img: DynamicImage, resampler: Resampler) -> DynamicImage {
+    let size = haar::NUM_PIXELS as u32;
+    match resampler {
+        // Reads the decoded pixels as RGBA one at a time, rather than converting a full size copy
+        Resampler::Gd => DynamicImage::ImageRgba8(image_resample(&img, size, size)),
+        Resampler::Filter(filter) => img.resize_exact(size, size, filter),
+    }
 }
 
 #[cfg(test)]
@@ -107,6 +427,209 @@ mod test {
 
     const PATH: &str = "reference/";
     const RESIZE: [&str; 3] = ["r_resize.txt", "g_resize.txt", "b_resize.txt"];
+    const ORIGINAL: [&str; 3] = ["r_original.txt", "g_original.txt", "b_original.txt"];
+
+    // Golden tests against the C++ iqdb. `*_original.txt` are the 200x200 pixels of the reference
+    // image as decoded by gd, `*_resize.txt` are the same after gd's resampling to 128x128, and
+    // `ret` is the danbooru iqdb response for adding the image.
+
+    /// The danbooru iqdb response for the reference image.
+    fn reference_ret() -> serde_json::Value {
+        let ret = std::fs::read_to_string(PATH.to_owned() + "ret").unwrap();
+        // The dump has a stray character after the json, only read the first value
+        serde_json::Deserializer::from_str(&ret)
+            .into_iter()
+            .next()
+            .unwrap()
+            .unwrap()
+    }
+
+    fn reference_signature() -> HaarSignature {
+        reference_ret()["hash"].as_str().unwrap().parse().unwrap()
+    }
+
+    /// Builds an image out of the per channel dumps in `files`.
+    fn reference_image(files: [&str; 3], size: u32) -> DynamicImage {
+        let [r, g, b] = files.map(|f| read_u8_vector_file(PATH.to_owned() + f));
+        assert_eq!(r.len(), (size * size) as usize);
+        let img = image::RgbImage::from_fn(size, size, |x, y| {
+            let i = (x + y * size) as usize;
+            image::Rgb([r[i], g[i], b[i]])
+        });
+        DynamicImage::ImageRgb8(img)
+    }
+
+    #[test]
+    fn golden_transform() {
+        // YIQ -> haar_2d -> calc_haar, starting from the image resampled by gd
+        let (a, b, c) = haar::transform_char(reference_image(RESIZE, 128));
+        let (avglf, _, _, _) = haar::calc_haar(a, b, c);
+        assert_eq!(avglf, reference_signature().avglf);
+    }
+
+    #[test]
+    fn golden_pipeline_avglf() {
+        let sig = HaarSignature::from(reference_image(ORIGINAL, 200));
+        assert_eq!(sig.avglf, reference_signature().avglf);
+    }
+
+    #[test]
+    fn from_rgb_buffer() {
+        let img = reference_image(ORIGINAL, 200);
+        let sig = HaarSignature::from_rgb(200, 200, img.to_rgb8().into_raw()).unwrap();
+        assert_eq!(sig.to_hash(), HaarSignature::from(img).to_hash());
+
+        let err = HaarSignature::from_rgb(200, 200, vec![0; 200 * 200]).unwrap_err();
+        assert_eq!(err.kind(), "validation");
+        assert!(HaarSignature::from_rgb(0, 0, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn golden_pipeline_signature() {
+        let sig = HaarSignature::from(reference_image(ORIGINAL, 200));
+        let reference = reference_signature();
+        assert_eq!(sig.sig0.sig, reference.sig0.sig);
+        assert_eq!(sig.sig1.sig, reference.sig1.sig);
+        assert_eq!(sig.sig2.sig, reference.sig2.sig);
+        assert_eq!(sig.to_hash(), reference_ret()["hash"]);
+    }
+
+    /// A signature that passes [`HaarSignature::validate`].
+    fn test_signature() -> HaarSignature {
+        let mut sig = HaarSignature::new();
+        sig.avglf = [0.5, -0.25, 0.125];
+        for i in 0..haar::NUM_COEFS {
+            sig.sig0.sig[i] = i as i16 + 1;
+            sig.sig1.sig[i] = -(i as i16) - 1;
+            sig.sig2.sig[i] = i as i16 + 100;
+        }
+        sig
+    }
+
+    #[test]
+    fn sig_bytes_roundtrip() {
+        let mut sig = test_signature();
+        sig.sig0.sig[0] = -16383;
+        sig.sig1.sig[1] = 300;
+        sig.sig2.sig[39] = -1;
+
+        let bytes = sig.sig_to_bytes();
+        assert_eq!(bytes.len(), SIG_BLOB_LEN);
+        assert_eq!(bytes[0], SIG_BLOB_VERSION);
+        assert_eq!(&bytes[1..3], &(-16383i16).to_le_bytes());
+
+        let decoded = HaarSignature::from_bytes(sig.avglf, &bytes).unwrap();
+        assert_eq!(decoded.avglf, sig.avglf);
+        assert_eq!(decoded.sig0.sig, sig.sig0.sig);
+        assert_eq!(decoded.sig1.sig, sig.sig1.sig);
+        assert_eq!(decoded.sig2.sig, sig.sig2.sig);
+    }
+
+    #[test]
+    fn sig_bytes_errors() {
+        let bytes = HaarSignature::new().sig_to_bytes();
+        assert_eq!(
+            HaarSignature::from_bytes([0.0; 3], &[]).unwrap_err(),
+            DecodeError::Length(0)
+        );
+        assert_eq!(
+            HaarSignature::from_bytes([0.0; 3], &bytes[..10]).unwrap_err(),
+            DecodeError::Length(10)
+        );
+        let mut future = bytes.clone();
+        future[0] = 2;
+        assert_eq!(
+            HaarSignature::from_bytes([0.0; 3], &future).unwrap_err(),
+            DecodeError::Version(2)
+        );
+
+        let sig = test_signature();
+        let bytes = sig.sig_to_bytes();
+        assert!(HaarSignature::from_bytes(sig.avglf, &bytes).is_ok());
+        assert!(matches!(
+            HaarSignature::from_bytes([f64::NAN, 0.0, 0.0], &bytes).unwrap_err(),
+            DecodeError::Invalid(InvalidSignature::Avgl(_))
+        ));
+        for coef in [0, 16384, i16::MIN] {
+            let mut bad = bytes.clone();
+            bad[3..5].copy_from_slice(&coef.to_le_bytes());
+            assert_eq!(
+                HaarSignature::from_bytes(sig.avglf, &bad).unwrap_err(),
+                DecodeError::Invalid(InvalidSignature::Coef(coef))
+            );
+        }
+    }
+
+    #[test]
+    fn hash_matches_reference() {
+        let ret = reference_ret();
+        let hash = ret["hash"].as_str().unwrap();
+
+        let sig: HaarSignature = hash.parse().unwrap();
+        assert_eq!(serde_json::to_value(&sig).unwrap(), ret["signature"]);
+        assert_eq!(sig.to_hash(), hash);
+    }
+
+    #[test]
+    fn hash_errors() {
+        let hash = HaarSignature::new().to_hash();
+        assert_eq!(hash.len(), HASH_PREFIX.len() + HASH_DIGITS);
+        assert_eq!(
+            hash[5..].parse::<HaarSignature>().unwrap_err(),
+            ParseHashError::Prefix
+        );
+        assert_eq!(
+            hash[..20].parse::<HaarSignature>().unwrap_err(),
+            ParseHashError::Length(15)
+        );
+        let bad = hash.replacen('0', "g", 1);
+        assert!(matches!(
+            bad.parse::<HaarSignature>().unwrap_err(),
+            ParseHashError::Hex(_)
+        ));
+
+        let sig = test_signature();
+        assert!(sig.to_hash().parse::<HaarSignature>().is_ok());
+        for coef in [0, 16384, i16::MIN] {
+            let mut bad = sig.clone();
+            bad.sig1.sig[5] = coef;
+            assert_eq!(
+                bad.to_hash().parse::<HaarSignature>().unwrap_err(),
+                ParseHashError::Invalid(InvalidSignature::Coef(coef))
+            );
+        }
+        let mut bad = sig;
+        bad.avglf[2] = f64::INFINITY;
+        assert_eq!(
+            bad.to_hash().parse::<HaarSignature>().unwrap_err(),
+            ParseHashError::Invalid(InvalidSignature::Avgl(f64::INFINITY))
+        );
+    }
+
+    #[test]
+    fn resampler_names() {
+        for (name, resampler) in Resampler::NAMES {
+            assert_eq!(name.parse::<Resampler>().unwrap(), resampler);
+            assert_eq!(resampler.to_string(), name);
+        }
+        assert_eq!(
+            "Lanczos3".parse(),
+            Ok(Resampler::Filter(FilterType::Lanczos3))
+        );
+        assert!("bicubic"
+            .parse::<Resampler>()
+            .unwrap_err()
+            .contains("expected one of gd, nearest"));
+    }
+
+    #[test]
+    fn resize_image_size() {
+        let img = DynamicImage::new_rgb8(300, 200);
+        for resampler in [Resampler::Gd, Resampler::Filter(FilterType::Triangle)] {
+            let resized = resize_image(img.clone(), resampler);
+            assert_eq!((resized.width(), resized.height()), (128, 128));
+        }
+    }
 
     #[test]
     fn testreference() {
@@ -121,7 +644,7 @@ mod test {
         if let Ok(lines) = read_lines(filename) {
             // Consumes the iterator, returns an (Optional) String
             // For these I'm using only 1 line
-            for line in lines.flatten() {
+            for line in lines.map_while(Result::ok) {
                 let iter = line[4..(line.len() - 1)].split(",");
 
                 for value in iter {
@@ -135,6 +658,17 @@ mod test {
         ret
     }
 
+    /// Reads a comma separated dump of channel values.
+    fn read_u8_vector_file(filename: String) -> Vec<u8> {
+        std::fs::read_to_string(filename)
+            .unwrap()
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(|value| value.parse::<u8>().unwrap())
+            .collect()
+    }
+
     // The output is wrapped in a Result to allow matching on errors.
     // Returns an Iterator to the Reader of the lines of the file.
     fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
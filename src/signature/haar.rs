@@ -1,20 +1,24 @@
-use image::imageops::FilterType;
 use image::{DynamicImage, GenericImageView};
-use itertools::izip;
 use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
-use std::f32::consts::FRAC_1_SQRT_2;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
 
 pub const N_COLORS: usize = 3; // 3 color channels (YIQ)
 pub const NUM_COEFS: usize = 40;
 pub const NUM_PIXELS: usize = 128;
 pub const NUM_PIXELS_SQUARED: usize = NUM_PIXELS.pow(2);
-pub const SCALING_FACTOR: f32 = 256.0 * 128.0;
+pub const SCALING_FACTOR: Unit = 256.0 * 128.0;
+// 1/sqrt(2), truncated the same way as in the C++ iqdb. Using the exact value shifts avglf.
+#[allow(clippy::approx_constant)]
+const HAAR_C: Unit = 0.7071;
 
 pub type Idx = i16;
-pub type Lumin = [f32; N_COLORS];
+pub type Lumin = [f64; N_COLORS];
+// The transform runs in double precision, like the C++ iqdb.
+pub type Unit = f64;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct SigT {
     #[serde(with = "BigArray")]
     pub sig: [i16; NUM_COEFS],
@@ -28,8 +32,8 @@ impl Default for SigT {
     }
 }
 
-pub fn transform_char(img: DynamicImage) -> (Vec<f32>, Vec<f32>, Vec<f32>) {
-    let img = img.resize_exact(128, 128, FilterType::Triangle);
+/// Converts a NUM_PIXELS x NUM_PIXELS image to YIQ, and applies the Haar transform to each channel.
+pub fn transform_char(img: DynamicImage) -> (Vec<Unit>, Vec<Unit>, Vec<Unit>) {
     let (mut a, mut b, mut c) = rgb_to_yiq_conversion(img);
     haar_2d(&mut a);
     haar_2d(&mut b);
@@ -43,17 +47,17 @@ pub fn transform_char(img: DynamicImage) -> (Vec<f32>, Vec<f32>, Vec<f32>) {
     (a, b, c)
 }
 
-fn haar_rows(a: &mut Vec<f32>) {
+fn haar_rows(a: &mut Vec<Unit>) {
     for i in (0..NUM_PIXELS_SQUARED).step_by(NUM_PIXELS) {
         let (mut h, mut h1): (usize, usize);
-        let mut c: f32 = 1.0;
+        let mut c: Unit = 1.0;
         h = NUM_PIXELS;
         while h > 1 {
             let (mut j1, mut j2, mut k): (usize, usize, usize) = (i, i, 0);
 
             h1 = h >> 1; // h1 = h / 2
-            c = c * FRAC_1_SQRT_2;
-            let mut t: Vec<f32> = vec![0.0; h1];
+            c *= HAAR_C;
+            let mut t: Vec<Unit> = vec![0.0; h1];
             while k < h1 {
                 let j21: usize = j2 + 1;
                 t[k] = (a[j2] - a[j21]) * c;
@@ -74,17 +78,17 @@ fn haar_rows(a: &mut Vec<f32>) {
     }
 }
 
-fn haar_columns(a: &mut Vec<f32>) {
+fn haar_columns(a: &mut [Unit]) {
     for i in 0..NUM_PIXELS {
         let (mut h, mut h1): (usize, usize);
-        let mut c: f32 = 1.0;
+        let mut c: Unit = 1.0;
         h = NUM_PIXELS;
         while h > 1 {
             let (mut j1, mut j2, mut k): (usize, usize, usize) = (i, i, 0);
 
             h1 = h >> 1; // h1 = h / 2
-            c = c * FRAC_1_SQRT_2;
-            let mut t: Vec<f32> = vec![0.0; h1];
+            c *= HAAR_C;
+            let mut t: Vec<Unit> = vec![0.0; h1];
             while k < h1 {
                 let j21: usize = j2 + NUM_PIXELS;
                 t[k] = (a[j2] - a[j21]) * c;
@@ -97,8 +101,8 @@ fn haar_columns(a: &mut Vec<f32>) {
 
             // Write back subtraction results:
             let mut j1 = i + (h1 * NUM_PIXELS);
-            for k in 0..h1 {
-                a[j1] = t[k];
+            for v in t.iter() {
+                a[j1] = *v;
                 j1 += NUM_PIXELS;
             }
             h = h1;
@@ -108,23 +112,23 @@ fn haar_columns(a: &mut Vec<f32>) {
     }
 }
 
-fn haar_2d(a: &mut Vec<f32>) {
+fn haar_2d(a: &mut Vec<Unit>) {
     haar_rows(a);
     haar_columns(a);
 }
 
-fn rgb_to_yiq_conversion(img: DynamicImage) -> (Vec<f32>, Vec<f32>, Vec<f32>) {
-    let mut cdata1: Vec<f32> = Vec::with_capacity(128 * 128);
-    let mut cdata2: Vec<f32> = Vec::with_capacity(128 * 128);
-    let mut cdata3: Vec<f32> = Vec::with_capacity(128 * 128);
+fn rgb_to_yiq_conversion(img: DynamicImage) -> (Vec<Unit>, Vec<Unit>, Vec<Unit>) {
+    let mut cdata1: Vec<Unit> = Vec::with_capacity(128 * 128);
+    let mut cdata2: Vec<Unit> = Vec::with_capacity(128 * 128);
+    let mut cdata3: Vec<Unit> = Vec::with_capacity(128 * 128);
 
     for p in img.pixels() {
         // The iteration order is x = 0 to width then y = 0 to height
         // RGB -> YIQ colorspace conversion; Y luminance, I,Q chrominance.
         // If RGB in [0..255] then Y in [0..255] and I,Q in [-127..127].
-        let r: f32 = p.2[0] as f32;
-        let g: f32 = p.2[1] as f32;
-        let b: f32 = p.2[2] as f32;
+        let r: Unit = p.2[0] as Unit;
+        let g: Unit = p.2[1] as Unit;
+        let b: Unit = p.2[2] as Unit;
 
         cdata1.push(0.299 * r + 0.587 * g + 0.114 * b);
         cdata2.push(0.596 * r - 0.275 * g - 0.321 * b);
@@ -134,14 +138,60 @@ fn rgb_to_yiq_conversion(img: DynamicImage) -> (Vec<f32>, Vec<f32>, Vec<f32>) {
     (cdata1, cdata2, cdata3)
 }
 
+/// A coefficient's magnitude and index. Ordered by magnitude, smallest first, so a BinaryHeap of
+/// them keeps the smallest magnitude on top.
+struct ValStruct {
+    d: Unit,
+    i: usize,
+}
+
+impl Ord for ValStruct {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.d.total_cmp(&self.d)
+    }
+}
+
+impl PartialOrd for ValStruct {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ValStruct {
+    fn eq(&self, other: &Self) -> bool {
+        self.d == other.d
+    }
+}
+
+impl Eq for ValStruct {}
+
 // Find the NUM_COEFS largest numbers in cdata[] (in magnitude that is)
-// and store their indices in sig[].
-fn get_m_largest(mut cdata: Vec<f32>) -> [i16; NUM_COEFS] {
-    cdata.sort_by(|a, b| ((a.abs()).partial_cmp(&(b.abs())).unwrap()).reverse());
+// and store their indices in sig[]. Negative coefficients get negative indices.
+// Skips i=0, since it goes into avglf.
+fn get_m_largest(cdata: &[Unit]) -> [Idx; NUM_COEFS] {
+    // Fill up the bounded queue. (Assuming NUM_PIXELS_SQUARED > NUM_COEFS)
+    let mut vq: BinaryHeap<ValStruct> = BinaryHeap::with_capacity(NUM_COEFS + 1);
+    for (i, val) in cdata.iter().enumerate().take(NUM_COEFS + 1).skip(1) {
+        vq.push(ValStruct { d: val.abs(), i });
+    }
+
+    // Queue is full (size is NUM_COEFS)
+    for (i, val) in cdata.iter().enumerate().skip(NUM_COEFS + 1) {
+        let d = val.abs();
+        if vq.peek().is_some_and(|top| d > top.d) {
+            // Make room by dropping smallest entry:
+            vq.pop();
+            vq.push(ValStruct { d, i });
+        }
+    }
 
-    let mut sig: [i16; NUM_COEFS] = [0; NUM_COEFS];
-    for (val, s) in izip!(cdata.iter(), sig.iter_mut()) {
-        *s = val.floor() as i16;
+    let mut sig: [Idx; NUM_COEFS] = [0; NUM_COEFS];
+    for (s, val) in sig.iter_mut().zip(vq) {
+        *s = if cdata[val.i] <= 0.0 {
+            -(val.i as Idx)
+        } else {
+            val.i as Idx
+        };
     }
     sig.sort();
 
@@ -154,26 +204,25 @@ fn get_m_largest(mut cdata: Vec<f32>) -> [i16; NUM_COEFS] {
 // The order of occurrence of the coordinates in sig doesn't matter.
 // Complexity is 3 x NUM_PIXELS^2 x 2log(NUM_COEFS).
 pub fn calc_haar(
-    cdata1: Vec<f32>,
-    cdata2: Vec<f32>,
-    cdata3: Vec<f32>,
+    cdata1: Vec<Unit>,
+    cdata2: Vec<Unit>,
+    cdata3: Vec<Unit>,
 ) -> (Lumin, SigT, SigT, SigT) {
-    let avglf: [f32; N_COLORS] = [cdata1[0], cdata2[0], cdata3[0]];
+    let avglf: Lumin = [cdata1[0], cdata2[0], cdata3[0]];
 
     // Color channel 1
-    // Skip i=0, since it goes into avglf
     let sig1: SigT = SigT {
-        sig: get_m_largest(cdata1[1..].to_vec()),
+        sig: get_m_largest(&cdata1),
     };
 
     // Color channel 2
     let sig2: SigT = SigT {
-        sig: get_m_largest(cdata2[1..].to_vec()),
+        sig: get_m_largest(&cdata2),
     };
 
     // Color channel 3
     let sig3: SigT = SigT {
-        sig: get_m_largest(cdata2[1..].to_vec()),
+        sig: get_m_largest(&cdata3),
     };
 
     (avglf, sig1, sig2, sig3)
@@ -182,6 +231,7 @@ pub fn calc_haar(
 #[cfg(test)]
 mod test {
     use super::*;
+    use itertools::izip;
     use std::fs::File;
     use std::io::{self, BufRead};
     use std::path::Path;
@@ -190,18 +240,79 @@ mod test {
     const RESIZE: [&str; 3] = ["r_resize.txt", "g_resize.txt", "b_resize.txt"];
     const ORIGINAL: [&str; 3] = ["r_buf.txt", "g_buf.txt", "b_buf.txt"];
 
+    #[test]
+    fn m_largest_indices() {
+        let mut cdata: Vec<Unit> = vec![0.0; NUM_PIXELS_SQUARED];
+        // The DC component is never part of the signature
+        cdata[0] = 1000.0;
+        let mut expected: Vec<Idx> = Vec::new();
+        for k in 0..NUM_COEFS {
+            let i = 100 + 7 * k;
+            let sign = if k % 3 == 0 { -1.0 } else { 1.0 };
+            cdata[i] = sign * (50.0 + k as Unit);
+            expected.push(if sign < 0.0 { -(i as Idx) } else { i as Idx });
+        }
+        // Smaller than all of the above
+        cdata[1] = 49.0;
+        cdata[NUM_PIXELS_SQUARED - 1] = -49.0;
+        expected.sort();
+
+        assert_eq!(get_m_largest(&cdata).to_vec(), expected);
+    }
+
+    #[test]
+    fn calc_haar_channels() {
+        let mut channels: Vec<Vec<Unit>> = vec![vec![0.0; NUM_PIXELS_SQUARED]; N_COLORS];
+        for (c, cdata) in channels.iter_mut().enumerate() {
+            cdata[0] = c as Unit;
+            for k in 0..NUM_COEFS {
+                cdata[1 + k + 1000 * c] = 1.0;
+            }
+        }
+        let [a, b, c]: [Vec<Unit>; 3] = channels.try_into().unwrap();
+        let (avglf, sig1, sig2, sig3) = calc_haar(a, b, c);
+
+        assert_eq!(avglf, [0.0, 1.0, 2.0]);
+        for (c, sig) in [sig1, sig2, sig3].iter().enumerate() {
+            let first = 1 + 1000 * c as Idx;
+            assert_eq!(sig.sig[0], first);
+            assert_eq!(sig.sig[NUM_COEFS - 1], first + NUM_COEFS as Idx - 1);
+        }
+    }
+
     #[test]
     fn testreference() {
         let img = read_i32_vector_file("reference/b_original.txt".to_string());
         println!("test {:?}", img);
     }
 
+    #[test]
+    fn yiq_of_gray_reference() {
+        // A gray pixel keeps its level as luminance and has no chrominance
+        for file in RESIZE.iter().chain(ORIGINAL.iter()) {
+            let levels = read_i32_vector_file(PATH.to_owned() + file);
+            let size = (levels.len() as f64).sqrt() as u32;
+            let img = image::GrayImage::from_fn(size, size, |x, y| {
+                image::Luma([levels[(x + y * size) as usize]])
+            });
+            let (y, i, q) = rgb_to_yiq_conversion(DynamicImage::ImageLuma8(img));
+
+            let levels: Vec<i32> = levels.iter().map(|&v| i32::from(v)).collect();
+            compare_vals_ints(
+                levels.clone(),
+                y.iter().map(|&v| v.round() as i32).collect(),
+            );
+            compare_vals(vec![0; levels.len()], i);
+            compare_vals(vec![0; levels.len()], q);
+        }
+    }
+
     fn read_i32_vector_file(filename: String) -> Vec<u8> {
         let mut ret: Vec<u8> = vec![];
         if let Ok(lines) = read_lines(filename) {
             // Consumes the iterator, returns an (Optional) String
             // For these I'm using only 1 line
-            for line in lines.flatten() {
+            for line in lines.map_while(Result::ok) {
                 let iter = line.split(",");
 
                 for value in iter {
@@ -215,7 +326,7 @@ mod test {
         ret
     }
 
-    fn compare_vals(v1: Vec<i32>, v2: Vec<f32>) {
+    fn compare_vals(v1: Vec<i32>, v2: Vec<Unit>) {
         for (reference, val) in izip!(v1, v2) {
             assert_eq!(reference, val.trunc() as i32);
         }